@@ -0,0 +1,460 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use tokio::prelude::*;
+use crate::config::{self, ConfigError, SprinklerType};
+use crate::http::{self, Url};
+use crate::metrics;
+use crate::report::{Report, Event, Transition, Outcome, MonitorStatus};
+#[cfg(test)]
+use crate::report::{Subject, PodIdentity};
+
+/// Time allowed for a mail to be handed over to the SMTP server
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How urgently someone should look at an alert
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Things are back to normal or going as planned
+    Info,
+    /// Something is wrong, and the sprinklers are dealing with it
+    Warning,
+    /// The sprinklers could not deal with it
+    Critical
+}
+
+/// A report of an agent on its way to people
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub severity: Severity,
+    pub sprinkler: SprinklerType,
+    /// Reporting host, or "cluster" for an incident spanning several
+    pub host: String,
+    /// Hosts affected by a cluster-level incident
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// One line for humans
+    pub summary: String,
+    /// Reported event, the latest one for an incident
+    pub event: Event
+}
+
+impl Alert {
+    pub fn from_report(report: &Report) -> Alert {
+        Alert {
+            severity: severity_of(&report.event),
+            sprinkler: report.sprinkler,
+            host: report.host.clone(),
+            hosts: Vec::new(),
+            time: report.time,
+            summary: format!("{}", report.event),
+            event: report.event.clone()
+        }
+    }
+
+    /// e.g. "[WARNING] DockerOOM k-prod-cpu-1.dsa.lan: pod jhub-prod/jupyter-alex Occurred"
+    pub fn title(&self) -> String {
+        format!("[{}] {:?} {}: {}", format!("{:?}", self.severity).to_uppercase(), self.sprinkler, self.host, self.summary)
+    }
+}
+
+fn severity_of(event: &Event) -> Severity {
+    match event {
+        Event::Anomaly { transition, .. } => match transition {
+            Transition::GaveUp => Severity::Critical,
+            Transition::Occurred | Transition::Fixing => Severity::Warning,
+            _ => Severity::Info
+        },
+        Event::Remediation { outcome: Outcome::Failed(_), .. } => Severity::Critical,
+        Event::Remediation { outcome: Outcome::Suppressed(_), .. } => Severity::Warning,
+        Event::Remediation { .. } => Severity::Info,
+        Event::Monitor { status: MonitorStatus::Degraded, .. } | Event::Monitor { status: MonitorStatus::BreakerTripped, .. } =>
+            Severity::Critical,
+        Event::Monitor { status: MonitorStatus::Deactivated, .. } => Severity::Warning,
+        Event::Monitor { .. } => Severity::Info,
+        Event::Offender { block: true, .. } => Severity::Critical,
+        Event::Offender { .. } => Severity::Warning
+    }
+}
+
+#[test]
+fn test_alert_severity() {
+    let subject = Subject::Pod(PodIdentity { namespace: String::from("jhub-prod"), name: String::from("jupyter-alex"), uid: String::from("5f3c"), ..Default::default() });
+    let anomaly = |transition| Event::Anomaly { subject: subject.clone(), transition };
+    assert_eq!(Severity::Warning, severity_of(&anomaly(Transition::Occurred)));
+    assert_eq!(Severity::Critical, severity_of(&anomaly(Transition::GaveUp)));
+    assert_eq!(Severity::Info, severity_of(&anomaly(Transition::Fixed)));
+    assert_eq!(Severity::Critical, severity_of(&Event::Remediation {
+        subject: subject.clone(),
+        container: String::from("4c01db0b339c"),
+        action: crate::report::Action::Kill,
+        outcome: Outcome::Failed(String::from("unable to kill: no such container"))
+    }));
+    assert_eq!(Severity::Critical, severity_of(&Event::Monitor { status: MonitorStatus::BreakerTripped, reason: None }));
+    assert_eq!(Severity::Info, severity_of(&Event::Monitor { status: MonitorStatus::Recovered, reason: None }));
+
+    let report = Report::new(SprinklerType::DockerOOM, "k-prod-cpu-1.dsa.lan", anomaly(Transition::Occurred));
+    assert_eq!(
+        "[WARNING] DockerOOM k-prod-cpu-1.dsa.lan: pod jhub-prod/jupyter-alex Occurred",
+        Alert::from_report(&report).title());
+}
+
+/// Somewhere alerts are sent
+pub trait AlertSink: Send + Sync {
+    fn send(&self, alert: &Alert) -> Box<dyn Future<Item = (), Error = String> + Send>;
+}
+
+/// POSTs alerts as JSON
+pub struct WebhookSink {
+    url: Url,
+    ca_cert: Option<native_tls::Certificate>
+}
+
+impl AlertSink for WebhookSink {
+    fn send(&self, alert: &Alert) -> Box<dyn Future<Item = (), Error = String> + Send> {
+        let body = serde_json::to_string(alert).expect("alerts are always serializable");
+        Box::new(http::request(&self.url, self.ca_cert.as_ref(), "POST", &self.url.path, &[], body))
+    }
+}
+
+/// Posts alerts to a Slack-compatible incoming webhook
+pub struct SlackSink {
+    url: Url,
+    ca_cert: Option<native_tls::Certificate>
+}
+
+impl AlertSink for SlackSink {
+    fn send(&self, alert: &Alert) -> Box<dyn Future<Item = (), Error = String> + Send> {
+        let body = serde_json::json!({ "text": alert.title() }).to_string();
+        Box::new(http::request(&self.url, self.ca_cert.as_ref(), "POST", &self.url.path, &[], body))
+    }
+}
+
+/// Mails alerts through an SMTP relay, which is trusted to take mail without TLS or authentication
+pub struct SmtpSink {
+    server: String,
+    from: String,
+    to: Vec<String>
+}
+
+impl AlertSink for SmtpSink {
+    fn send(&self, alert: &Alert) -> Box<dyn Future<Item = (), Error = String> + Send> {
+        let mut script = vec![
+            (None, 220),
+            (Some(format!("HELO {}", hostname())), 250),
+            (Some(format!("MAIL FROM:<{}>", &self.from)), 250)
+        ];
+        script.extend(self.to.iter().map(|to| (Some(format!("RCPT TO:<{}>", to)), 250)));
+        script.push((Some(String::from("DATA")), 354));
+        script.push((Some(self.compose(alert)), 250));
+        script.push((Some(String::from("QUIT")), 221));
+
+        let server = self.server.clone();
+        Box::new(http::resolve(server.clone())
+            .and_then(|addr| tokio::net::TcpStream::connect(&addr))
+            .and_then(|stream| converse(stream, script))
+            .timeout(SMTP_TIMEOUT)
+            .map_err(move |e| match e.into_inner() {
+                Some(e) => format!("SMTP {}: {}", server, e),
+                None => format!("SMTP {}: timed out", server)
+            }))
+    }
+}
+
+impl SmtpSink {
+    /// Mail of an alert, terminated and dot-stuffed for DATA
+    fn compose(&self, alert: &Alert) -> String {
+        let mut mail = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            &self.from,
+            self.to.iter().map(|to| format!("<{}>", to)).collect::<Vec<String>>().join(", "),
+            alert.title().replace(&['\r', '\n'][..], " "),
+            alert.time.to_rfc2822());
+        let body = serde_json::to_string_pretty(alert).expect("alerts are always serializable");
+        for line in alert.summary.lines().chain(std::iter::once("")).chain(body.lines()) {
+            if line.starts_with('.') { mail.push('.'); }
+            mail.push_str(line);
+            mail.push_str("\r\n");
+        }
+        mail.push('.');
+        mail
+    }
+}
+
+fn hostname() -> String {
+    sys_info::hostname().unwrap_or_else(|_| String::from("localhost"))
+}
+
+/// Run an SMTP script, sending each command (if any) and expecting a reply with its code
+fn converse<S>(stream: S, script: Vec<(Option<String>, u16)>) -> impl Future<Item = (), Error = io::Error>
+    where S: AsyncRead + AsyncWrite {
+    let (reader, writer) = stream.split();
+    stream::iter_ok(script).fold((io::BufReader::new(reader), writer), |(reader, writer), (command, expected)| {
+        let verb = command.as_ref().map_or(String::from("greeting"), |command| {
+            String::from(command.split(&[' ', ':'][..]).next().unwrap_or_default())
+        });
+        let sent = match command {
+            Some(command) => future::Either::A(tokio::io::write_all(writer, format!("{}\r\n", command).into_bytes())
+                .map(|(writer, _)| writer)),
+            None => future::Either::B(future::ok(writer))
+        };
+        sent.and_then(|writer| read_reply(reader).map(move |(reader, reply)| (reader, writer, reply)))
+            .and_then(move |(reader, writer, reply)| {
+                if reply.starts_with(&expected.to_string()) { Ok((reader, writer)) }
+                else { Err(io::Error::new(io::ErrorKind::Other, format!("{} refused: {}", verb, reply))) }
+            })
+    }).map(|_| ())
+}
+
+/// Read a possibly multiline reply, returning its last line
+fn read_reply<R>(reader: io::BufReader<R>) -> impl Future<Item = (io::BufReader<R>, String), Error = io::Error>
+    where R: AsyncRead {
+    future::loop_fn(reader, |reader| {
+        tokio::io::read_until(reader, b'\n', Vec::new()).and_then(|(reader, line)| {
+            let line = String::from(String::from_utf8_lossy(&line).trim_end());
+            if line.is_empty() { Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")) }
+            else if line.as_bytes().get(3) == Some(&b'-') { Ok(future::Loop::Continue(reader)) }
+            else { Ok(future::Loop::Break((reader, line))) }
+        })
+    })
+}
+
+/// Where to send alerts, and which
+///
+/// ```toml
+/// [[alerts]]
+/// sink = { type = "slack", url = "https://hooks.slack.com/services/T0/B0/x" }
+/// min_severity = "critical"
+/// sprinklers = ["DockerOOM"]
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkConfig {
+    pub sink: SinkKind,
+    /// Alerts less severe than this are not sent
+    #[serde(default = "SinkConfig::default_min_severity")]
+    pub min_severity: Severity,
+    /// Sprinklers whose alerts are sent, or all if empty
+    #[serde(default)]
+    pub sprinklers: Vec<SprinklerType>
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkKind {
+    /// POST alerts as JSON to a URL
+    Webhook { url: String, ca_cert: Option<PathBuf> },
+    /// Post alerts to a Slack-compatible incoming webhook
+    Slack { url: String, ca_cert: Option<PathBuf> },
+    /// Mail alerts through an SMTP relay at <host>:<port>
+    Smtp { server: String, from: String, to: Vec<String> }
+}
+
+impl SinkConfig {
+    fn default_min_severity() -> Severity {
+        Severity::Warning
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.sink {
+            SinkKind::Webhook { url, .. } | SinkKind::Slack { url, .. } => Url::parse(url).map(|_| ()).map_err(|e| format!("url {}", e)),
+            SinkKind::Smtp { server, .. } if server.split(':').count() != 2 =>
+                Err(format!("server must be <host>:<port>, got {:?}", server)),
+            SinkKind::Smtp { to, .. } if to.is_empty() => Err(String::from("to must not be empty")),
+            SinkKind::Smtp { .. } => Ok(())
+        }
+    }
+
+    /// Whether an alert is to be sent to this sink
+    pub fn accepts(&self, alert: &Alert) -> bool {
+        alert.severity >= self.min_severity
+            && (self.sprinklers.is_empty() || self.sprinklers.contains(&alert.sprinkler))
+    }
+
+    pub fn build(&self) -> Result<Box<dyn AlertSink>, ConfigError> {
+        let load = |ca_cert: &Option<PathBuf>| -> Result<Option<native_tls::Certificate>, ConfigError> {
+            match ca_cert {
+                Some(path) => Ok(Some(config::load_cert(path)?)),
+                None => Ok(None)
+            }
+        };
+        let url = |url: &str| Url::parse(url).map_err(|e| ConfigError::Invalid(format!("url {}", e)));
+        Ok(match &self.sink {
+            SinkKind::Webhook { url: u, ca_cert } => Box::new(WebhookSink { url: url(u)?, ca_cert: load(ca_cert)? }),
+            SinkKind::Slack { url: u, ca_cert } => Box::new(SlackSink { url: url(u)?, ca_cert: load(ca_cert)? }),
+            SinkKind::Smtp { server, from, to } => Box::new(SmtpSink { server: server.clone(), from: from.clone(), to: to.clone() })
+        })
+    }
+}
+
+#[test]
+fn test_sink_filters() {
+    let config: SinkConfig = toml::from_str(r#"
+        sink = { type = "webhook", url = "http://localhost:8080/alerts" }
+        sprinklers = ["DockerOOM"]
+    "#).unwrap();
+    assert_eq!(Severity::Warning, config.min_severity);
+    let alert = |sprinkler, status| Alert::from_report(&Report::new(sprinkler, "k-prod-cpu-1.dsa.lan", Event::Monitor { status, reason: None }));
+    assert!(config.accepts(&alert(SprinklerType::DockerOOM, MonitorStatus::Degraded)));
+    assert!(!config.accepts(&alert(SprinklerType::DockerOOM, MonitorStatus::Recovered)));
+    assert!(!config.accepts(&alert(SprinklerType::CommCheck, MonitorStatus::Degraded)));
+
+    assert!(toml::from_str::<SinkConfig>(r#"sink = { type = "smtp", server = "mail.dsa.lan:25", from = "sprinkler@dsa.lan", to = [] }"#)
+        .unwrap().validate().is_err());
+    assert!(toml::from_str::<SinkConfig>(r#"sink = { type = "pager" }"#).is_err());
+}
+
+/// A sink along with its filters
+struct Installed {
+    name: String,
+    config: SinkConfig,
+    sink: Box<dyn AlertSink>
+}
+
+lazy_static! {
+    /// Sinks of this process
+    static ref SINKS: RwLock<Arc<Vec<Installed>>> = RwLock::new(Arc::new(Vec::new()));
+}
+
+/// Replace the sinks of this process, keeping the current ones if any fails to build
+pub fn install(configs: &[SinkConfig]) -> Result<(), ConfigError> {
+    let mut sinks = Vec::new();
+    for (i, config) in configs.iter().enumerate() {
+        let sink = config.build().map_err(|e| ConfigError::Invalid(format!("alerts[{}]: {}", i, e)))?;
+        let kind = match &config.sink {
+            SinkKind::Webhook { .. } => "webhook",
+            SinkKind::Slack { .. } => "slack",
+            SinkKind::Smtp { .. } => "smtp"
+        };
+        sinks.push(Installed { name: format!("alerts[{}] ({})", i, kind), config: config.clone(), sink });
+    }
+    *SINKS.write().unwrap() = Arc::new(sinks);
+    Ok(())
+}
+
+/// Send an alert to every sink that accepts it, logging failures
+pub fn dispatch(alert: &Alert) {
+    let sinks = SINKS.read().unwrap().clone();
+    for installed in sinks.iter().filter(|installed| installed.config.accepts(alert)) {
+        let name = installed.name.clone();
+        tokio::spawn(installed.sink.send(alert).then(move |result| {
+            let outcome = if result.is_ok() { "sent" } else { "failed" };
+            metrics::inc("sprinkler_alerts_total", &[("sink", &name), ("result", outcome)]);
+            if let Err(e) = result {
+                warn!("{}: unable to send alert: {}", name, e);
+            }
+            Ok(())
+        }));
+    }
+}
+
+#[cfg(test)]
+fn mock_alert() -> Alert {
+    Alert::from_report(&Report::new(SprinklerType::DockerOOM, "k-prod-cpu-1.dsa.lan", Event::Monitor {
+        status: MonitorStatus::BreakerTripped,
+        reason: Some(String::from("20 fixes within 60s, notifying only for 600s"))
+    }))
+}
+
+#[test]
+fn test_webhook_sink() {
+    let (url, server) = http::fake_server("HTTP/1.1 204 No Content\r\n\r\n");
+    let config = SinkConfig {
+        sink: SinkKind::Webhook { url: format!("{}/hooks/sprinkler", url), ca_cert: None },
+        min_severity: Severity::Info,
+        sprinklers: Vec::new()
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(Ok(()), runtime.block_on(config.build().unwrap().send(&mock_alert())));
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /hooks/sprinkler HTTP/1.0\r\n"));
+    let body = http::body_of(&request);
+    assert_eq!("critical", body["severity"]);
+    assert_eq!("k-prod-cpu-1.dsa.lan", body["host"]);
+    assert_eq!("breaker_tripped", body["event"]["status"]);
+}
+
+#[test]
+fn test_slack_sink() {
+    let (url, server) = http::fake_server("HTTP/1.1 404 Not Found\r\n\r\nno_team");
+    let config = SinkConfig {
+        sink: SinkKind::Slack { url: format!("{}/services/T0/B0/x", url), ca_cert: None },
+        min_severity: Severity::Info,
+        sprinklers: Vec::new()
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(
+        Err(String::from("POST /services/T0/B0/x: HTTP/1.1 404 Not Found")),
+        runtime.block_on(config.build().unwrap().send(&mock_alert())));
+
+    let body = http::body_of(&server.join().unwrap());
+    assert_eq!(
+        "[CRITICAL] DockerOOM k-prod-cpu-1.dsa.lan: BreakerTripped: 20 fixes within 60s, notifying only for 600s",
+        body["text"]);
+}
+
+/// SMTP server that takes one mail, and hands the conversation back
+#[cfg(test)]
+fn fake_smtp_server(refuse: Option<&'static str>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("{}", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let mut lines = Vec::new();
+        stream.write_all(b"220 mail.dsa.lan ESMTP\r\n").unwrap();
+        let mut data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 { break; }
+            let line = String::from(line.trim_end_matches("\r\n"));
+            let reply: &[u8] = if data {
+                if line == "." { data = false; b"250 2.0.0 Ok: queued\r\n" } else { b"" }
+            }
+            else if refuse.map_or(false, |verb| line.starts_with(verb)) { b"550 5.1.1 Recipient address rejected\r\n" }
+            else if line.starts_with("DATA") { data = true; b"354 End data with <CR><LF>.<CR><LF>\r\n" }
+            else if line.starts_with("QUIT") { b"221 2.0.0 Bye\r\n" }
+            else { b"250-mail.dsa.lan\r\n250 OK\r\n" };
+            stream.write_all(reply).unwrap();
+            let quit = line == "QUIT";
+            lines.push(line);
+            if quit { break; }
+        }
+        lines
+    });
+    (addr, server)
+}
+
+#[test]
+fn test_smtp_sink() {
+    let (addr, server) = fake_smtp_server(None);
+    let sink = SmtpSink {
+        server: addr,
+        from: String::from("sprinkler@dsa.lan"),
+        to: vec![String::from("ops@dsa.lan"), String::from("alex@dsa.lan")]
+    };
+    let mut alert = mock_alert();
+    alert.summary = String::from(".hidden\nsecond line");
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(Ok(()), runtime.block_on(sink.send(&alert)));
+
+    let lines = server.join().unwrap();
+    assert_eq!("MAIL FROM:<sprinkler@dsa.lan>", lines[1]);
+    assert_eq!(vec!["RCPT TO:<ops@dsa.lan>", "RCPT TO:<alex@dsa.lan>", "DATA"], lines[2..5].to_vec());
+    assert!(lines.contains(&String::from("Subject: [CRITICAL] DockerOOM k-prod-cpu-1.dsa.lan: .hidden second line")));
+    assert!(lines.contains(&String::from("..hidden"))); // Dot-stuffed
+    assert_eq!(vec![".", "QUIT"], lines[lines.len() - 2..].to_vec());
+
+    let (addr, server) = fake_smtp_server(Some("RCPT"));
+    let sink = SmtpSink { server: addr.clone(), ..sink };
+    assert_eq!(
+        Err(format!("SMTP {}: RCPT refused: 550 5.1.1 Recipient address rejected", addr)),
+        runtime.block_on(sink.send(&alert)));
+    server.join().unwrap();
+}
@@ -0,0 +1,250 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How deep container cgroups are looked for, e.g. kubepods.slice/kubepods-burstable.slice/<pod>/<container>
+const SEARCH_DEPTH: usize = 6;
+
+/// Period of CPU quotas in microseconds, unless one is set already
+const CPU_PERIOD: u64 = 100_000;
+
+/// What is done to a container instead of taking it down
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Restriction {
+    /// Suspend all its processes
+    Freeze,
+    /// Reclaim its memory above `memory_high` bytes and limit it to `cpu_quota` CPUs
+    Throttle {
+        memory_high: Option<u64>,
+        cpu_quota: Option<f32>
+    }
+}
+
+impl Restriction {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Restriction::Freeze => Ok(()),
+            Restriction::Throttle { memory_high: None, cpu_quota: None } =>
+                Err(String::from("throttle needs memory_high, cpu_quota or both")),
+            Restriction::Throttle { cpu_quota: Some(cpu_quota), .. } if cpu_quota.is_nan() || *cpu_quota <= 0.0 =>
+                Err(format!("cpu_quota must be positive, got {}", cpu_quota)),
+            Restriction::Throttle { .. } => Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Version {
+    /// One hierarchy per controller
+    V1,
+    /// The unified hierarchy
+    V2
+}
+
+/// Settings overwritten by a restriction, which are written back to lift it
+#[derive(Debug)]
+pub struct Saved(Vec<(PathBuf, String)>);
+
+impl Saved {
+    /// Write back the settings in the reverse order of the restriction
+    pub fn restore(self) -> io::Result<()> {
+        for (path, value) in self.0.into_iter().rev() {
+            std::fs::write(&path, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cgroup filesystem mounted at `root`
+pub struct Cgroups {
+    root: PathBuf,
+    version: Version
+}
+
+impl Cgroups {
+    pub fn open(root: &Path) -> io::Result<Cgroups> {
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", root.display())));
+        }
+        let version = if root.join("cgroup.controllers").exists() { Version::V2 } else { Version::V1 };
+        Ok(Cgroups { root: PathBuf::from(root), version })
+    }
+
+    /// Apply a restriction to a container, returning what it replaced
+    pub fn restrict(&self, container: &str, restriction: &Restriction) -> io::Result<Saved> {
+        let mut saved = Saved(Vec::new());
+        let result = match restriction {
+            Restriction::Freeze => match self.version {
+                Version::V2 => self.set(&mut saved, "", container, "cgroup.freeze", String::from("1")),
+                Version::V1 => self.set(&mut saved, "freezer", container, "freezer.state", String::from("FROZEN"))
+            },
+            Restriction::Throttle { memory_high, cpu_quota } => {
+                self.throttle_memory(&mut saved, container, *memory_high)
+                    .and_then(|_| self.throttle_cpu(&mut saved, container, *cpu_quota))
+            }
+        };
+        match result {
+            Ok(()) => Ok(saved),
+            Err(e) => { // Do not leave the container half restricted
+                if let Err(e) = saved.restore() {
+                    warn!("Unable to undo the restriction of {}: {}", container, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn throttle_memory(&self, saved: &mut Saved, container: &str, memory_high: Option<u64>) -> io::Result<()> {
+        let memory_high = match memory_high { Some(memory_high) => memory_high, None => return Ok(()) };
+        match self.version {
+            Version::V2 => self.set(saved, "", container, "memory.high", memory_high.to_string()),
+            // The closest v1 has to memory.high
+            Version::V1 => self.set(saved, "memory", container, "memory.soft_limit_in_bytes", memory_high.to_string())
+        }
+    }
+
+    fn throttle_cpu(&self, saved: &mut Saved, container: &str, cpu_quota: Option<f32>) -> io::Result<()> {
+        let cpu_quota = match cpu_quota { Some(cpu_quota) => cpu_quota, None => return Ok(()) };
+        let quota = |period: u64| std::cmp::max(1000, ((period as f64) * (cpu_quota as f64)) as u64);
+        match self.version {
+            Version::V2 => {
+                let dir = self.find("", container)?;
+                let current = std::fs::read_to_string(dir.join("cpu.max"))?;
+                let period = current.split_whitespace().nth(1).and_then(|p| p.parse().ok()).unwrap_or(CPU_PERIOD);
+                self.set(saved, "", container, "cpu.max", format!("{} {}", quota(period), period))
+            }
+            Version::V1 => {
+                let dir = self.find("cpu", container)?;
+                let period = std::fs::read_to_string(dir.join("cpu.cfs_period_us"))?.trim().parse().unwrap_or(CPU_PERIOD);
+                self.set(saved, "cpu", container, "cpu.cfs_quota_us", quota(period).to_string())
+            }
+        }
+    }
+
+    /// Write a setting of a container, saving the current value first
+    fn set(&self, saved: &mut Saved, controller: &str, container: &str, file: &str, value: String) -> io::Result<()> {
+        let path = self.find(controller, container)?.join(file);
+        let current = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, value)?;
+        saved.0.push((path, String::from(current.trim())));
+        Ok(())
+    }
+
+    /// Locate the cgroup of a container in the hierarchy of a controller
+    ///
+    /// Docker names it after the container id with the cgroupfs driver, and docker-<id>.scope with the
+    /// systemd driver.
+    fn find(&self, controller: &str, container: &str) -> io::Result<PathBuf> {
+        let base = match self.version {
+            Version::V2 => self.root.clone(),
+            Version::V1 => self.root.join(controller)
+        };
+        let scope = format!("-{}.scope", container);
+        let mut dirs = vec![(base.clone(), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() { continue; }
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name == container || name.ends_with(&scope) {
+                    return Ok(entry.path());
+                }
+                if depth + 1 < SEARCH_DEPTH {
+                    dirs.push((entry.path(), depth + 1));
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("no cgroup of {} in {}", container, base.display())))
+    }
+}
+
+/// Temporary cgroupfs laid out like kubelet with the systemd driver
+#[cfg(test)]
+fn fake_cgroupfs(version: Version, container: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("sprinkler-test-{}-cgroup-{:?}", std::process::id(), version));
+    let _ = std::fs::remove_dir_all(&root);
+    let pod = "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5f3c.slice";
+    let scope = format!("{}/docker-{}.scope", pod, container);
+    let files: Vec<(String, &str)> = match version {
+        Version::V2 => vec![
+            (String::from("cgroup.controllers"), "cpu memory pids\n"),
+            (format!("{}/cgroup.freeze", pod), "0\n"),
+            (format!("{}/cgroup.freeze", scope), "0\n"),
+            (format!("{}/memory.high", scope), "max\n"),
+            (format!("{}/cpu.max", scope), "200000 100000\n")
+        ],
+        Version::V1 => vec![
+            (format!("freezer/{}/freezer.state", scope), "THAWED\n"),
+            (format!("memory/{}/memory.soft_limit_in_bytes", scope), "9223372036854771712\n"),
+            (format!("cpu/{}/cpu.cfs_period_us", scope), "50000\n"),
+            (format!("cpu/{}/cpu.cfs_quota_us", scope), "-1\n")
+        ]
+    };
+    for (path, contents) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    root
+}
+
+#[cfg(test)]
+fn read_setting(root: &Path, path: &str) -> String {
+    let pod = "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5f3c.slice";
+    String::from(std::fs::read_to_string(root.join(path.replace("<scope>", &format!("{}/docker-4c01db0b339c.scope", pod))))
+        .unwrap().trim())
+}
+
+#[test]
+fn test_cgroup_v2() {
+    let root = fake_cgroupfs(Version::V2, "4c01db0b339c");
+    let cgroups = Cgroups::open(&root).unwrap();
+    assert_eq!(Version::V2, cgroups.version);
+
+    let saved = cgroups.restrict("4c01db0b339c", &Restriction::Freeze).unwrap();
+    assert_eq!("1", read_setting(&root, "<scope>/cgroup.freeze"));
+    saved.restore().unwrap();
+    assert_eq!("0", read_setting(&root, "<scope>/cgroup.freeze"));
+
+    let throttle = Restriction::Throttle { memory_high: Some(1 << 30), cpu_quota: Some(0.5) };
+    let saved = cgroups.restrict("4c01db0b339c", &throttle).unwrap();
+    assert_eq!("1073741824", read_setting(&root, "<scope>/memory.high"));
+    assert_eq!("50000 100000", read_setting(&root, "<scope>/cpu.max"));
+    saved.restore().unwrap();
+    assert_eq!("max", read_setting(&root, "<scope>/memory.high"));
+    assert_eq!("200000 100000", read_setting(&root, "<scope>/cpu.max"));
+
+    assert_eq!(io::ErrorKind::NotFound, cgroups.restrict("7a21", &Restriction::Freeze).unwrap_err().kind());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cgroup_v1() {
+    let root = fake_cgroupfs(Version::V1, "4c01db0b339c");
+    let cgroups = Cgroups::open(&root).unwrap();
+    assert_eq!(Version::V1, cgroups.version);
+
+    let saved = cgroups.restrict("4c01db0b339c", &Restriction::Freeze).unwrap();
+    assert_eq!("FROZEN", read_setting(&root, "freezer/<scope>/freezer.state"));
+    saved.restore().unwrap();
+    assert_eq!("THAWED", read_setting(&root, "freezer/<scope>/freezer.state"));
+
+    let throttle = Restriction::Throttle { memory_high: None, cpu_quota: Some(0.5) };
+    let saved = cgroups.restrict("4c01db0b339c", &throttle).unwrap();
+    assert_eq!("9223372036854771712", read_setting(&root, "memory/<scope>/memory.soft_limit_in_bytes"));
+    assert_eq!("25000", read_setting(&root, "cpu/<scope>/cpu.cfs_quota_us"));
+    saved.restore().unwrap();
+    assert_eq!("-1", read_setting(&root, "cpu/<scope>/cpu.cfs_quota_us"));
+
+    // Nothing is left restricted when a step fails
+    std::fs::remove_file(root.join("cpu").join(
+        "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5f3c.slice/docker-4c01db0b339c.scope/cpu.cfs_period_us")).unwrap();
+    let throttle = Restriction::Throttle { memory_high: Some(1 << 30), cpu_quota: Some(0.5) };
+    assert!(cgroups.restrict("4c01db0b339c", &throttle).is_err());
+    assert_eq!("9223372036854771712", read_setting(&root, "memory/<scope>/memory.soft_limit_in_bytes"));
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -1,7 +1,18 @@
-use sprinkler_api::{Sprinkler, SprinklerBuilder, SprinklerOptions, CommCheck};
-use crate::docker_oom::DockerOOM;
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use sprinkler_api::{Sprinkler, SprinklerOptions, CommCheck};
+use crate::docker_oom::{DockerOOM, DockerOOMPolicy};
+use crate::kube::KubeConfig;
+use crate::alerts::SinkConfig;
+use crate::incidents::CorrelationPolicy;
+use crate::status::Watched;
+#[cfg(test)]
+use crate::meter::MeterKind;
 
-// pub const FNAME_CONFIG: &str = "/etc/sprinkler.conf.d/config.toml";
+pub const FNAME_CONFIG: &str = "/etc/sprinkler.conf.d/config.toml";
+pub const FNAME_MASTER_CERT: &str = "/etc/sprinkler.conf.d/master.crt";
+pub const FNAME_OUTBOX: &str = "/var/lib/sprinkler/outbox.journal";
+pub const FNAME_EVENTS: &str = "/var/lib/sprinkler/events.log";
 
 pub fn setup_logger(verbose: u64) -> Result<(), fern::InitError> {
     fern::Dispatch::new()
@@ -25,45 +36,418 @@ pub fn setup_logger(verbose: u64) -> Result<(), fern::InitError> {
     Ok(())
 }
 
-pub fn get_sprinklers() -> Vec<Box<dyn Sprinkler>> {
-    const MASTER_ADDR: &str = "bridge.dsa.lan:3777";
-    let mut builder = SprinklerBuilder::new(SprinklerOptions{ master_addr: String::from(MASTER_ADDR), ..Default::default() });
-
-    // parse FNAME_CONFIG and add triggers
-    let sprinklers: Vec<Box<dyn Sprinkler>> = vec![
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-1.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-2.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-3.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-4.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-5.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-6.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-7.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-8.dsa.lan"))),
-        Box::new(builder.build::<CommCheck>(String::from("k-prod-cpu-9.dsa.lan"))),
-
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-1.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-2.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-3.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-4.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-5.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-6.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-7.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-8.dsa.lan"))),
-        Box::new(builder.build::<DockerOOM>(String::from("k-prod-cpu-9.dsa.lan")))
-    ];
-
-    return sprinklers;
-}
-
-// pub fn get_sprinklers() -> Vec<Box<dyn Sprinkler>> {
-//     const MASTER_ADDR: &str = "desktop-cyberpower.localdomain:3777";
-//     let mut builder = SprinklerBuilder::new(SprinklerOptions{ master_addr: String::from(MASTER_ADDR), ..Default::default() });
-
-//     // parse FNAME_CONFIG and add triggers
-//     let sprinklers: Vec<Box<dyn Sprinkler>> = vec![
-//         Box::new(builder.build::<CommCheck>(String::from("latitude-5289"))),
-//         Box::new(builder.build::<DockerOOM>(String::from("latitude-5289")))
-//     ];
-
-//     return sprinklers;
-// }
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// A file the configuration refers to, e.g. a certificate, cannot be loaded
+    File(std::path::PathBuf, String),
+    Invalid(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "cannot read configuration: {}", e),
+            ConfigError::Parse(e) => write!(f, "cannot parse configuration: {}", e),
+            ConfigError::File(path, reason) => write!(f, "{}: {}", path.display(), reason),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self { ConfigError::Io(e) }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self { ConfigError::Parse(e) }
+}
+
+/// Read a PEM certificate, naming it in errors
+pub fn load_cert(path: &std::path::Path) -> Result<native_tls::Certificate, ConfigError> {
+    let pem = std::fs::read(path).map_err(|e| ConfigError::File(path.to_path_buf(), e.to_string()))?;
+    native_tls::Certificate::from_pem(&pem)
+        .map_err(|e| ConfigError::File(path.to_path_buf(), format!("invalid certificate: {}", e)))
+}
+
+#[test]
+fn test_load_cert() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}-master.crt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let e = load_cert(&path).err().unwrap();
+    assert!(format!("{}", e).starts_with(&format!("{}: ", path.display())), "{}", e);
+    std::fs::write(&path, "not a certificate").unwrap();
+    let e = load_cert(&path).err().unwrap();
+    assert!(format!("{}", e).starts_with(&format!("{}: invalid certificate", path.display())), "{}", e);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Types of sprinklers that can be deployed onto a host
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SprinklerType {
+    CommCheck,
+    DockerOOM
+}
+
+impl SprinklerType {
+    /// Order in which sprinkler ids are assigned, which has to agree between master and agents
+    const ORDER: [SprinklerType; 2] = [SprinklerType::CommCheck, SprinklerType::DockerOOM];
+}
+
+/// Contents of FNAME_CONFIG, e.g.
+///
+/// ```toml
+/// master_addr = "bridge.dsa.lan:3777"
+/// listen_addr = "0.0.0.0:3777"
+///
+/// [docker_oom]
+/// oom_rate = 10.0
+///
+/// [[hosts]]
+/// hostname = "k-prod-cpu-1.dsa.lan"
+/// sprinklers = ["CommCheck", "DockerOOM"]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where agents reach the master
+    pub master_addr: String,
+    /// Where the master listens
+    #[serde(default = "Config::default_listen_addr")]
+    #[allow(dead_code)] // Unused by agents
+    pub listen_addr: std::net::SocketAddr,
+    /// Where agents serve /metrics
+    #[serde(default = "Config::default_agent_http_addr")]
+    #[allow(dead_code)] // Unused by the master
+    pub agent_http_addr: std::net::SocketAddr,
+    /// Where the master serves /metrics and the status of the fleet
+    #[serde(default = "Config::default_master_http_addr")]
+    #[allow(dead_code)] // Unused by agents
+    pub master_http_addr: std::net::SocketAddr,
+    /// CA bundle with which agents verify the master
+    #[serde(default = "Config::default_master_cert")]
+    #[allow(dead_code)] // Unused by the master
+    pub master_cert: std::path::PathBuf,
+    /// Journal of notifications pending delivery on agents
+    #[serde(default = "Config::default_outbox")]
+    #[allow(dead_code)] // Unused by the master
+    pub outbox: std::path::PathBuf,
+    /// Log of the reports the master has received
+    #[serde(default = "Config::default_events")]
+    #[allow(dead_code)] // Unused by agents
+    pub events: std::path::PathBuf,
+    /// Seconds without hearing from a CommCheck agent after which the status of the fleet shows its host offline
+    #[serde(default = "Config::default_offline_after")]
+    #[allow(dead_code)] // Unused by agents
+    pub offline_after: u64,
+    /// Default policy of DockerOOM
+    #[serde(default)]
+    pub docker_oom: DockerOOMPolicy,
+    /// Kubernetes API server through which agents fix pods
+    #[serde(default)]
+    pub kubernetes: Option<KubeConfig>,
+    /// Where the master sends alerts
+    #[serde(default)]
+    #[allow(dead_code)] // Unused by agents
+    pub alerts: Vec<SinkConfig>,
+    /// How the master merges reports of a workload from several nodes
+    #[serde(default)]
+    #[allow(dead_code)] // Unused by agents
+    pub correlation: CorrelationPolicy,
+    /// Host inventory
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub hostname: String,
+    /// Sprinklers running on this host
+    pub sprinklers: Vec<SprinklerType>,
+    /// Overrides the default policy of DockerOOM on this host
+    pub docker_oom: Option<DockerOOMPolicy>
+}
+
+impl HostConfig {
+    pub fn docker_oom_policy<'a>(&'a self, config: &'a Config) -> &'a DockerOOMPolicy {
+        self.docker_oom.as_ref().unwrap_or(&config.docker_oom)
+    }
+}
+
+impl Config {
+    fn default_listen_addr() -> std::net::SocketAddr {
+        "0.0.0.0:3777".parse().unwrap()
+    }
+
+    fn default_agent_http_addr() -> std::net::SocketAddr {
+        "0.0.0.0:3778".parse().unwrap()
+    }
+
+    fn default_master_http_addr() -> std::net::SocketAddr {
+        "0.0.0.0:3779".parse().unwrap()
+    }
+
+    fn default_master_cert() -> std::path::PathBuf {
+        std::path::PathBuf::from(FNAME_MASTER_CERT)
+    }
+
+    fn default_outbox() -> std::path::PathBuf {
+        std::path::PathBuf::from(FNAME_OUTBOX)
+    }
+
+    fn default_events() -> std::path::PathBuf {
+        std::path::PathBuf::from(FNAME_EVENTS)
+    }
+
+    fn default_offline_after() -> u64 {
+        30
+    }
+
+    /// Read and validate a configuration file
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        Config::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse and validate configuration text
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.master_addr.split(':').count() != 2 {
+            return Err(ConfigError::Invalid(format!("master_addr must be <host>:<port>, got {:?}", self.master_addr)));
+        }
+        self.docker_oom.validate().map_err(|e| ConfigError::Invalid(format!("docker_oom.{}", e)))?;
+        if let Some(kubernetes) = &self.kubernetes {
+            kubernetes.validate().map_err(|e| ConfigError::Invalid(format!("kubernetes.{}", e)))?;
+        }
+        if self.kubernetes.is_none() && (self.docker_oom.needs_kubernetes()
+            || self.hosts.iter().filter_map(|host| host.docker_oom.as_ref()).any(DockerOOMPolicy::needs_kubernetes)) {
+            return Err(ConfigError::Invalid(String::from("fixing pods through Kubernetes requires a [kubernetes] section")));
+        }
+        for (i, sink) in self.alerts.iter().enumerate() {
+            sink.validate().map_err(|e| ConfigError::Invalid(format!("alerts[{}].sink.{}", i, e)))?;
+        }
+        self.correlation.validate().map_err(|e| ConfigError::Invalid(format!("correlation.{}", e)))?;
+        for (i, host) in self.hosts.iter().enumerate() {
+            if host.hostname.is_empty() {
+                return Err(ConfigError::Invalid(format!("hosts[{}] has an empty hostname", i)));
+            }
+            if self.hosts[..i].iter().any(|h| h.hostname == host.hostname) {
+                return Err(ConfigError::Invalid(format!("host {} is listed more than once", host.hostname)));
+            }
+            if let Some(policy) = &host.docker_oom {
+                policy.validate().map_err(|e| ConfigError::Invalid(format!("{}: docker_oom.{}", host.hostname, e)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything that determines how a sprinkler is built
+#[derive(Clone, Debug, PartialEq)]
+pub struct SprinklerSpec {
+    pub id: usize,
+    pub typ: SprinklerType,
+    pub hostname: String,
+    pub master_addr: String,
+    pub docker_oom: Option<DockerOOMPolicy>,
+    pub kubernetes: Option<KubeConfig>
+}
+
+impl Config {
+    /// Specifications of all sprinklers, numbered in the order of the configuration
+    pub fn specs(&self) -> Vec<SprinklerSpec> {
+        // Sprinklers are grouped by host so that ids stay stable as the inventory grows
+        let mut specs = Vec::new();
+        for host in self.hosts.iter() {
+            for typ in SprinklerType::ORDER.iter().filter(|typ| host.sprinklers.contains(typ)) {
+                specs.push(SprinklerSpec {
+                    id: specs.len(),
+                    typ: *typ,
+                    hostname: host.hostname.clone(),
+                    master_addr: self.master_addr.clone(),
+                    docker_oom: match typ {
+                        SprinklerType::DockerOOM => Some(host.docker_oom_policy(self).clone()),
+                        _ => None
+                    },
+                    kubernetes: match typ {
+                        SprinklerType::DockerOOM => self.kubernetes.clone(),
+                        _ => None
+                    }
+                });
+            }
+        }
+        specs
+    }
+}
+
+/// Build the sprinkler of a specification under its id
+pub fn build_sprinkler(spec: SprinklerSpec) -> Box<dyn Sprinkler> {
+    let options = SprinklerOptions { master_addr: spec.master_addr, _id: spec.id, _hostname: spec.hostname, ..Default::default() };
+    match spec.typ {
+        SprinklerType::CommCheck => Box::new(Watched::<CommCheck>::build(options)),
+        SprinklerType::DockerOOM => Box::new(DockerOOM::build(options)
+            .with_policy(spec.docker_oom.unwrap_or_default())
+            .with_kubernetes(spec.kubernetes))
+    }
+}
+
+#[test]
+fn test_config_parse() {
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["DockerOOM"]
+    "#).unwrap();
+    assert_eq!(config.listen_addr, Config::default_listen_addr());
+    assert_eq!(config.hosts.len(), 2);
+
+    assert_eq!(config.hosts[0].docker_oom_policy(&config), &Default::default());
+
+    let sprinklers: Vec<Box<dyn Sprinkler>> = config.specs().into_iter().map(build_sprinkler).collect();
+    assert_eq!(
+        vec![(0, "k-prod-cpu-1.dsa.lan"), (1, "k-prod-cpu-1.dsa.lan"), (2, "k-prod-cpu-2.dsa.lan")],
+        sprinklers.iter().map(|s| (s.id(), s.hostname())).collect::<Vec<(usize, &str)>>());
+}
+
+#[test]
+fn test_config_docker_oom_policy() {
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [docker_oom]
+        oom_rate = 5.0
+        max_retry = 10
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["DockerOOM"]
+        docker_oom = { panic_rate = 100.0, panic_meter = { type = "ewma", time_constant = 2.0 } }
+    "#).unwrap();
+    let policy = config.hosts[0].docker_oom_policy(&config);
+    assert_eq!(policy.oom_rate, 5.0);
+    assert_eq!(policy.max_retry, 10);
+    assert_eq!(policy.divider, DockerOOMPolicy::default().divider);
+    let policy = config.hosts[1].docker_oom_policy(&config);
+    assert_eq!(policy.oom_rate, DockerOOMPolicy::default().oom_rate);
+    assert_eq!(policy.panic_rate, 100.0);
+    assert_eq!(policy.panic_meter, MeterKind::Ewma { time_constant: 2.0 });
+    assert_eq!(policy.oom_meter, MeterKind::Fixed);
+
+    match Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [docker_oom]
+        oom_meter = { type = "sliding_window", buckets = 0 }
+    "#) {
+        Err(ConfigError::Invalid(_)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn test_config_unknown_sprinkler() {
+    match Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["DockerPanic"]
+    "#) {
+        Err(ConfigError::Parse(_)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn test_config_duplicate_host() {
+    match Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["DockerOOM"]
+    "#) {
+        Err(ConfigError::Invalid(_)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn test_config_kubernetes() {
+    match Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["DockerOOM"]
+        docker_oom = { remediation = { pods = "evict" } }
+    "#) {
+        Err(ConfigError::Invalid(_)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [kubernetes]
+        api_server = "https://k-master.dsa.lan:6443"
+        token_file = "/etc/sprinkler.conf.d/kube.token"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+        docker_oom = { remediation = { pods = "evict" } }
+    "#).unwrap();
+    let specs = config.specs();
+    assert_eq!(None, specs[0].kubernetes);
+    assert_eq!(config.kubernetes, specs[1].kubernetes);
+}
+
+#[test]
+fn test_config_alerts() {
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[alerts]]
+        sink = { type = "slack", url = "https://hooks.slack.com/services/T0/B0/x" }
+        min_severity = "critical"
+
+        [[alerts]]
+        sink = { type = "smtp", server = "mail.dsa.lan:25", from = "sprinkler@dsa.lan", to = ["ops@dsa.lan"] }
+        sprinklers = ["DockerOOM"]
+    "#).unwrap();
+    assert_eq!(2, config.alerts.len());
+    assert_eq!(vec![SprinklerType::DockerOOM], config.alerts[1].sprinklers);
+
+    match Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[alerts]]
+        sink = { type = "webhook", url = "hooks.dsa.lan/sprinkler" }
+    "#) {
+        Err(ConfigError::Invalid(reason)) => assert!(reason.starts_with("alerts[0].sink.url"), "{}", reason),
+        other => panic!("unexpected {:?}", other)
+    }
+}
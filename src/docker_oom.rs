@@ -1,150 +1,335 @@
-use std::io::Write;
 use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
 use std::collections::HashMap;
 use tokio::prelude::*;
+use serde::Deserialize;
 use sprinkler_api::*;
+use crate::notification::Notification;
+use crate::alerts;
+use crate::config::SprinklerType;
+use crate::incidents;
+use crate::store;
+use crate::status;
+use crate::metrics;
+use crate::meter::{RateMeter, MeterKind, Clock, SystemClock};
+use crate::kube::{self, KubeConfig};
+use crate::cgroup::{self, Cgroups, Restriction};
+use crate::rules::{Rule, RuleAction};
+use crate::limiter::{Limiter, LimitPolicy, Verdict};
+use crate::offenders::{Offenders, OffenderPolicy};
+#[cfg(test)]
+use crate::meter::{ManualClock, EventRateMeter};
+use crate::report::{Report, Event, Subject, PodIdentity, Action, Outcome, MonitorStatus};
 
 #[derive(Clone)]
 pub struct DockerOOM {
     options: Arc<SprinklerOptions>,
-    _deactivate: Arc<Mutex<bool>>
+    policy: Arc<DockerOOMPolicy>,
+    /// Kubernetes API server through which pods are fixed
+    kubernetes: Arc<Option<KubeConfig>>,
+    /// Pace of fixes on this node
+    limiter: Arc<Mutex<Limiter>>,
+    /// Fixes made lately by workload or user
+    offenders: Arc<Mutex<Offenders>>,
+    /// Containers under restriction, with what lifts it
+    restricted: Arc<Mutex<HashMap<String, Restricted>>>,
+    /// Queue of containers to be fixed, closed upon deactivation
+    fixes: Arc<Mutex<Option<futures::sync::mpsc::UnboundedSender<Fix>>>>,
+    _deactivate: Arc<Mutex<Option<futures::sync::oneshot::Sender<()>>>>
 }
 
-pub trait ImportantExt {
-    fn is_important(&self) -> bool;
+/// A container to be fixed
+#[derive(Clone)]
+struct Fix {
+    container: String,
+    subject: Subject,
+    /// How to fix it instead of what the remediation settings say
+    action: Option<RuleAction>,
+    /// Grace period of a repeat offender instead of remediation.stop_grace
+    stop_grace: Option<u64>
 }
 
-pub struct EventRateMeter {
-    count: usize,                        // Event counter
-    t0: chrono::DateTime<chrono::Local>, // Starting point of this period
-    last_rate: f32,                      // Last reading
-    interval: chrono::Duration,          // Measurement interval
-    pub state: Anomaly                   // aka Is this (ab)normal?
+/// A restriction in place
+struct Restricted {
+    subject: Subject,
+    /// Action that lifts the restriction
+    action: Action,
+    saved: cgroup::Saved,
+    since: std::time::Instant
 }
 
-impl Default for EventRateMeter {
-    fn default() -> Self {
-        EventRateMeter {
-            count: 0,
-            t0: chrono::Local::now(),
-            last_rate: 0f32,
-            interval: chrono::Duration::seconds(1),
-            state: Anomaly::Negative
-        }
-    }
+/// Maximum number of containers being fixed at the same time
+const FIX_CONCURRENCY: usize = 8;
+
+/// Time allowed for dockerd to answer a stop request beyond the grace period
+const STOP_TIMEOUT_SLACK: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Detection thresholds and escalation limits of DockerOOM
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerOOMPolicy {
+    /// OOM event rate (Hz) of a container above which it is considered anomalous
+    pub oom_rate: f32,
+    /// Rate (Hz) of all other docker events above which docker is considered panicking
+    pub panic_rate: f32,
+    /// Number of fixes attempted before declaring out-of-control
+    pub max_retry: usize,
+    /// Handle one out of this many anomalous OOM events of a pod
+    pub divider: usize,
+    /// Handle one out of this many anomalous OOM events of containers not managed by Kubernetes
+    pub unidentified_divider: usize,
+    /// Seconds after which the meter of a quiet pod in normal state is dropped
+    pub meter_ttl: u64,
+    /// How OOM event rates are measured
+    pub oom_meter: MeterKind,
+    /// How the rate of all other docker events is measured
+    pub panic_meter: MeterKind,
+    /// Report what would be fixed instead of fixing anything
+    pub dry_run: bool,
+    /// Namespaces in which fixes are only reported, even if dry_run is off
+    pub dry_run_namespaces: Vec<String>,
+    /// How containers are fixed
+    pub remediation: RemediationPolicy,
+    /// Rules for specific containers, the first matching one of which applies
+    pub rules: Vec<Rule>,
+    /// Let workloads override the rules with sprinkler.k8s/* annotations
+    pub honor_annotations: bool,
+    /// How fast this node may fix containers
+    pub limits: LimitPolicy,
+    /// How workloads that keep getting fixed are dealt with
+    pub offenders: OffenderPolicy
 }
 
-impl EventRateMeter {
-    /// Trigger the meter counter
-    pub fn tick(&mut self) {
-        self.count += 1;
-        if chrono::Local::now() - self.t0 > self.interval {
-            self.last_rate = self.read();
-            self.count = 0;
-            self.t0 = chrono::Local::now();
+impl Default for DockerOOMPolicy {
+    fn default() -> Self {
+        DockerOOMPolicy {
+            oom_rate: 10.0,
+            panic_rate: 70.0,
+            max_retry: 20,
+            divider: 5,
+            unidentified_divider: 15,
+            meter_ttl: 600,
+            oom_meter: MeterKind::Fixed,
+            panic_meter: MeterKind::Fixed,
+            dry_run: false,
+            dry_run_namespaces: Vec::new(),
+            remediation: Default::default(),
+            rules: Vec::new(),
+            honor_annotations: true,
+            limits: Default::default(),
+            offenders: Default::default()
         }
     }
+}
 
-    fn dt(&self) -> f32 {
-        ((((chrono::Local::now() - self.t0).num_milliseconds()) as f32) / 1e3) + 1e-8
-    }
+/// Escalation ladder of a fix: stop, kill if it does not stop, then remove
+///
+/// Containers of pods may instead be taken down by evicting or deleting their pods, so that kubelet
+/// does not restart them right away.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemediationPolicy {
+    /// Seconds a container is given to stop before it is killed, 0 to kill it right away
+    pub stop_grace: u64,
+    /// Remove the container once it is down, or keep it around for forensics
+    pub remove: bool,
+    /// How containers of pods are fixed
+    pub pods: PodRemediation,
+    /// Restrict containers through their cgroups instead of taking them down
+    pub restrict: Option<Restriction>,
+    /// Seconds after which a restriction is lifted
+    pub hold: u64,
+    /// Where the cgroup filesystem is mounted
+    pub cgroup_root: std::path::PathBuf
+}
 
-    /// Compute event frequency (Hz)
-    pub fn read(&self) -> f32 {
-        if chrono::Local::now() - self.t0 < self.interval * 2 {
-            if self.count < 6 && self.last_rate > 0.0 { self.last_rate }
-            else { (self.count as f32) / self.dt() }
+impl Default for RemediationPolicy {
+    fn default() -> Self {
+        RemediationPolicy {
+            stop_grace: 10,
+            remove: true,
+            pods: PodRemediation::Docker,
+            restrict: None,
+            hold: 300,
+            cgroup_root: std::path::PathBuf::from(cgroup::CGROUP_ROOT)
         }
-        else { 0.0 }
     }
 }
 
-#[test]
-fn test_event_rate_gt70_hz_1() {
-    let mut meter = EventRateMeter::default();
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PodRemediation {
+    /// Through the docker ladder like any other container
+    Docker,
+    /// Evict the pod, which respects its disruption budget
+    Evict,
+    /// Delete the pod
+    Delete
+}
 
-    let duration = std::time::Duration::from_millis(13); // 76.9Hz
-    // Frequency generator
-    for _ in 0..25 {
-        meter.tick();
-        std::thread::sleep(duration);
+impl RemediationPolicy {
+    /// Action taken on the pod of a container, if any
+    fn pod_action(&self, fix: &Fix) -> Option<Action> {
+        match (&fix.subject, fix.action, self.pods) {
+            (Subject::Pod(_), Some(RuleAction::Evict), _) | (Subject::Pod(_), None, PodRemediation::Evict) => Some(Action::Evict),
+            (Subject::Pod(_), None, PodRemediation::Delete) => Some(Action::Delete),
+            _ => None
+        }
     }
 
-    assert!(meter.read() > 70.0);
-}
-
-#[test]
-#[should_panic]
-fn test_event_rate_gt70_hz_2() {
-    let mut meter = EventRateMeter::default();
+    /// Restriction applied to a container, if any, which repeat offenders are past
+    fn restriction(&self, fix: &Fix) -> Option<&Restriction> {
+        if fix.action.is_some() || fix.stop_grace.is_some() { None } else { self.restrict.as_ref() }
+    }
 
-    let duration = std::time::Duration::from_millis(15); // 66.7Hz
-    // Frequency generator
-    for _ in 0..25 {
-        meter.tick();
-        std::thread::sleep(duration);
+    /// Seconds a container is given to stop
+    fn stop_grace(&self, fix: &Fix) -> u64 {
+        fix.stop_grace.unwrap_or(self.stop_grace)
     }
 
-    assert!(meter.read() > 70.0);
+    /// Steps taken when the container stops in time
+    fn plan(&self, fix: &Fix) -> Vec<Action> {
+        match self.restriction(fix) {
+            Some(Restriction::Freeze) => return vec![Action::Freeze],
+            Some(Restriction::Throttle { .. }) => return vec![Action::Throttle],
+            None => ()
+        }
+        if let Some(action) = self.pod_action(fix) { return vec![action]; }
+        let mut plan = vec![if self.stop_grace(fix) > 0 { Action::Stop } else { Action::Kill }];
+        if self.remove { plan.push(Action::Remove); }
+        plan
+    }
 }
 
 #[test]
-fn test_event_rate_gt70_hz_1_low_count() {
-    let mut meter = EventRateMeter::default();
+fn test_remediation_plan() {
+    let pod = Fix { container: String::from("4c01db0b339c"), subject: Subject::Pod(Default::default()), action: None, stop_grace: None };
+    let container = Fix { subject: Subject::Container { name: String::from("registry") }, ..pod.clone() };
+    assert_eq!(vec![Action::Stop, Action::Remove], RemediationPolicy::default().plan(&pod));
+    assert_eq!(vec![Action::Kill], RemediationPolicy { stop_grace: 0, remove: false, ..Default::default() }.plan(&container));
+
+    let policy = RemediationPolicy { pods: PodRemediation::Evict, ..Default::default() };
+    assert_eq!(vec![Action::Evict], policy.plan(&pod));
+    assert_eq!(vec![Action::Stop, Action::Remove], policy.plan(&container));
+
+    let policy = RemediationPolicy { restrict: Some(Restriction::Freeze), ..policy };
+    assert_eq!(vec![Action::Freeze], policy.plan(&pod));
+    // Repeat offenders are not let off with a restriction
+    assert_eq!(vec![Action::Kill, Action::Remove], policy.plan(&Fix { stop_grace: Some(0), ..container.clone() }));
+
+    // Rules take precedence
+    assert_eq!(vec![Action::Stop, Action::Remove], policy.plan(&Fix { action: Some(RuleAction::Kill), ..pod.clone() }));
+    let policy = RemediationPolicy { restrict: None, ..policy };
+    assert_eq!(vec![Action::Evict], policy.plan(&Fix { action: Some(RuleAction::Evict), ..pod }));
+}
 
-    let duration = std::time::Duration::from_millis(13); // 76.9Hz
-    // Frequency generator
-    for _ in 0..4 {
-        meter.tick();
-        std::thread::sleep(duration);
+impl DockerOOMPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        self.oom_meter.validate().map_err(|e| format!("oom_meter: {}", e))?;
+        self.panic_meter.validate().map_err(|e| format!("panic_meter: {}", e))?;
+        self.limits.validate().map_err(|e| format!("limits: {}", e))?;
+        self.offenders.validate().map_err(|e| format!("offenders: {}", e))?;
+        match &self.remediation.restrict {
+            Some(restriction) => restriction.validate().map_err(|e| format!("remediation.restrict: {}", e)),
+            None => Ok(())
+        }
     }
 
-    assert!(meter.read() > 70.0);
-}
+    /// Whether some containers are to be fixed through the Kubernetes API
+    pub fn needs_kubernetes(&self) -> bool {
+        self.remediation.pods != PodRemediation::Docker
+            || self.rules.iter().any(|rule| rule.action == Some(RuleAction::Evict))
+    }
 
-#[test]
-#[should_panic]
-fn test_event_rate_gt70_hz_2_low_count() {
-    let mut meter = EventRateMeter::default();
+    /// Settings of the first rule that matches a container, filled in with those of the policy
+    ///
+    /// Overrides the container carries take precedence over the rule.
+    fn decide(&self, attributes: &HashMap<String, String>) -> Decision {
+        let default = Rule::default();
+        let rule = self.rules.iter().find(|rule| rule.matches(attributes)).unwrap_or(&default);
+        let rule = if self.honor_annotations { Rule::from_overrides(attributes).or(rule) } else { rule.clone() };
+        Decision {
+            action: rule.action,
+            oom_rate: rule.oom_rate.unwrap_or(self.oom_rate),
+            max_retry: rule.max_retry.unwrap_or(self.max_retry),
+            divider: rule.divider.unwrap_or(self.divider)
+        }
+    }
 
-    let duration = std::time::Duration::from_millis(15); // 66.7Hz
-    // Frequency generator
-    for _ in 0..4 {
-        meter.tick();
-        std::thread::sleep(duration);
+    /// Whether fixes of a subject are only to be reported
+    pub fn is_dry_run(&self, subject: &Subject) -> bool {
+        self.dry_run || match subject {
+            Subject::Pod(pod) => self.dry_run_namespaces.contains(&pod.namespace),
+            _ => false
+        }
     }
+}
 
-    assert!(meter.read() > 70.0);
+/// What applies to a container according to the policy table
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Decision {
+    action: Option<RuleAction>,
+    oom_rate: f32,
+    max_retry: usize,
+    divider: usize
 }
 
 #[test]
-fn test_event_rate_gt70_hz_1_high_count() {
-    let mut meter = EventRateMeter::default();
-
-    let duration = std::time::Duration::from_millis(13); // 76.9Hz
-    // Frequency generator
-    for _ in 0..300 {
-        meter.tick();
-        std::thread::sleep(duration);
-    }
-
-    assert!(meter.read() > 70.0);
+fn test_policy_decide() {
+    let policy: DockerOOMPolicy = toml::from_str(r#"
+        oom_rate = 10.0
+
+        [[rules]]
+        namespace = "kube-system"
+        action = "ignore"
+
+        [[rules]]
+        namespace = "jhub-*"
+        pod = "jupyter-*"
+        action = "evict"
+        oom_rate = 5.0
+        max_retry = 3
+
+        [[rules]]
+        labels = { "app" = "batch-*" }
+        action = "notify"
+    "#).unwrap();
+    let attributes = |pairs: &[(&str, &str)]| pairs.iter()
+        .map(|(k, v)| (String::from(*k), String::from(*v))).collect::<HashMap<String, String>>();
+    let pod = |namespace, name| attributes(&[("io.kubernetes.pod.namespace", namespace), ("io.kubernetes.pod.name", name)]);
+
+    assert_eq!(Some(RuleAction::Ignore), policy.decide(&pod("kube-system", "coredns-5c98db65d4-8xjx2")).action);
+    assert_eq!(
+        Decision { action: Some(RuleAction::Evict), oom_rate: 5.0, max_retry: 3, divider: policy.divider },
+        policy.decide(&pod("jhub-prod", "jupyter-alex")));
+    assert_eq!(Some(RuleAction::Notify), policy.decide(&attributes(&[("app", "batch-etl")])).action);
+    // The default rule
+    assert_eq!(
+        Decision { action: None, oom_rate: 10.0, max_retry: policy.max_retry, divider: policy.divider },
+        policy.decide(&pod("jhub-prod", "hub-7d9f8b6c5-x2x4z")));
+    assert!(policy.needs_kubernetes());
+
+    let mut exempted = pod("jhub-prod", "jupyter-alex");
+    exempted.insert(String::from("annotation.sprinkler.k8s/ignore"), String::from("true"));
+    assert_eq!(Some(RuleAction::Ignore), policy.decide(&exempted).action);
+    let policy = DockerOOMPolicy { honor_annotations: false, ..policy };
+    assert_eq!(Some(RuleAction::Evict), policy.decide(&exempted).action);
 }
 
 #[test]
-#[should_panic]
-fn test_event_rate_gt70_hz_2_high_count() {
-    let mut meter = EventRateMeter::default();
-
-    let duration = std::time::Duration::from_millis(15); // 66.7Hz
-    // Frequency generator
-    for _ in 0..300 {
-        meter.tick();
-        std::thread::sleep(duration);
-    }
+fn test_dry_run_policy() {
+    let pod = |namespace: &str| Subject::Pod(PodIdentity { namespace: String::from(namespace), ..Default::default() });
+    let policy = DockerOOMPolicy { dry_run_namespaces: vec![String::from("jhub-staging")], ..Default::default() };
+    assert!(policy.is_dry_run(&pod("jhub-staging")));
+    assert!(!policy.is_dry_run(&pod("jhub-prod")));
+    assert!(!policy.is_dry_run(&Subject::Container { name: String::from("jhub-staging") }));
+
+    let policy = DockerOOMPolicy { dry_run: true, ..Default::default() };
+    assert!(policy.is_dry_run(&pod("jhub-prod")));
+    assert!(policy.is_dry_run(&Subject::Container { name: String::from("registry") }));
+}
 
-    assert!(meter.read() > 70.0);
+pub trait ImportantExt {
+    fn is_important(&self) -> bool;
 }
 
 #[derive(Default)]
@@ -207,17 +392,18 @@ fn test_freq_divider_3() {
 
 #[test]
 fn test_combo_1() {
-    let mut meter = EventRateMeter::default();
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
     let mut divider = FrequencyDivider { interval: 4, ..Default::default() };
 
-    let duration = std::time::Duration::from_millis(12); // 83.3Hz
+    let duration = chrono::Duration::milliseconds(12); // 83.3Hz
     // Frequency generator
     for _ in 0..100 {
         divider.tick();
         if divider.read() {
             meter.tick();
         }
-        std::thread::sleep(duration);
+        clock.advance(duration);
     }
 
     assert!(meter.read() > 18.6);
@@ -226,17 +412,18 @@ fn test_combo_1() {
 #[test]
 #[should_panic]
 fn test_combo_2() {
-    let mut meter = EventRateMeter::default();
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
     let mut divider = FrequencyDivider { interval: 4, ..Default::default() };
 
-    let duration = std::time::Duration::from_millis(14); // 71.4Hz
+    let duration = chrono::Duration::milliseconds(14); // 71.4Hz
     // Frequency generator
     for _ in 0..100 {
         divider.tick();
         if divider.read() {
             meter.tick();
         }
-        std::thread::sleep(duration);
+        clock.advance(duration);
     }
 
     assert!(meter.read() > 18.6);
@@ -244,17 +431,18 @@ fn test_combo_2() {
 
 #[test]
 fn test_combo_passthru_1() {
-    let mut meter = EventRateMeter::default();
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
     let mut divider = FrequencyDivider::default();
 
-    let duration = std::time::Duration::from_millis(12); // 83.3Hz
+    let duration = chrono::Duration::milliseconds(12); // 83.3Hz
     // Frequency generator
     for _ in 0..100 {
         divider.tick();
         if divider.read() {
             meter.tick();
         }
-        std::thread::sleep(duration);
+        clock.advance(duration);
     }
 
     assert!(meter.read() > 70.0);
@@ -263,17 +451,18 @@ fn test_combo_passthru_1() {
 #[test]
 #[should_panic]
 fn test_combo_passthru_2() {
-    let mut meter = EventRateMeter::default();
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
     let mut divider = FrequencyDivider::default();
 
-    let duration = std::time::Duration::from_millis(14); // 71.4Hz
+    let duration = chrono::Duration::milliseconds(14); // 71.4Hz
     // Frequency generator
     for _ in 0..100 {
         divider.tick();
         if divider.read() {
             meter.tick();
         }
-        std::thread::sleep(duration);
+        clock.advance(duration);
     }
 
     assert!(meter.read() > 70.0);
@@ -288,13 +477,142 @@ impl ImportantExt for AnomalyTransition {
     }
 }
 
-type MeterSet = Arc<RwLock<HashMap<String, Mutex<(EventRateMeter, FrequencyDivider)>>>>;
+/// Rate of events of a subject along with its anomaly state
+struct Meter {
+    rate: Box<dyn RateMeter>,
+    divider: FrequencyDivider,
+    state: Anomaly
+}
+
+impl Meter {
+    fn new(rate: Box<dyn RateMeter>, divider: usize) -> Self {
+        Meter { rate, divider: FrequencyDivider { interval: divider, ..Default::default() }, state: Anomaly::Negative }
+    }
+}
+
+type MeterSet = Arc<RwLock<HashMap<String, Mutex<Meter>>>>;
+
+/// Meters of events that are not told apart by pod: "!" for docker events other than OOMs,
+/// and "." for OOMs of containers outside of Kubernetes
+fn shared_meters<C: Clock + Clone + Send + 'static>(policy: &DockerOOMPolicy, clock: C) -> HashMap<String, Mutex<Meter>> {
+    let mut meters = HashMap::new();
+    meters.insert(String::from("!"), Mutex::new(Meter::new(policy.panic_meter.build_with_clock(clock.clone()), 0)));
+    meters.insert(String::from("."), Mutex::new(Meter::new(policy.oom_meter.build_with_clock(clock), policy.unidentified_divider)));
+    meters
+}
+
+/// How often idle meters are collected
+const METER_GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Drop per-pod meters that have not been triggered within the TTL, whatever their state
+///
+/// Pods that are gone for good never trigger their meters again, so a meter left in Fixing(n)
+/// would otherwise be kept forever.
+fn collect_meters(meters: &mut HashMap<String, Mutex<Meter>>, ttl: chrono::Duration) {
+    meters.retain(|pod_name, meter| {
+        if pod_name == "!" || pod_name == "." { return true; } // Shared meters are permanent
+        meter.get_mut().unwrap().rate.idle() <= ttl
+    });
+}
+
+#[test]
+fn test_collect_meters() {
+    let clock = ManualClock::default();
+    let meter = || Meter::new(MeterKind::Fixed.build_with_clock(clock.clone()), 0);
+    let mut meters = HashMap::new();
+    meters.insert(String::from("."), Mutex::new(meter()));
+    meters.insert(String::from("jupyter-idle"), Mutex::new(meter()));
+    meters.insert(String::from("jupyter-killed"), Mutex::new(Meter { state: Anomaly::Fixing(1), ..meter() }));
+    meters.insert(String::from("jupyter-gave-up"), Mutex::new(Meter { state: Anomaly::Fixing(20), ..meter() }));
+    clock.advance(chrono::Duration::seconds(3600));
+    meters.insert(String::from("jupyter-active"), Mutex::new(meter()));
+    meters.insert(String::from("jupyter-fixing"), Mutex::new(Meter { state: Anomaly::Fixing(1), ..meter() }));
+    collect_meters(&mut meters, chrono::Duration::seconds(600));
+
+    let mut remaining = meters.keys().map(|k| k.as_str()).collect::<Vec<&str>>();
+    remaining.sort();
+    assert_eq!(vec![".", "jupyter-active", "jupyter-fixing"], remaining);
+}
+
+#[test]
+fn test_shared_meters() {
+    // Thresholds that are never reached, so that nothing is reported
+    let policy = DockerOOMPolicy { oom_rate: std::f32::INFINITY, panic_rate: std::f32::INFINITY, ..Default::default() };
+    let sprinkler = DockerOOM::build(SprinklerOptions::default()).with_policy(policy);
+    let clock = ManualClock::default();
+    let meters: MeterSet = Arc::new(RwLock::new(shared_meters(&sprinkler.policy, clock.clone())));
+    let idle = |name: &str| meters.read().unwrap()[name].lock().unwrap().rate.idle().num_seconds();
+    clock.advance(chrono::Duration::seconds(10));
+
+    // Other docker events and OOMs outside of Kubernetes are counted apart
+    for _ in 0..3 {
+        sprinkler.handle_other_panic(meters.clone());
+    }
+    assert_eq!((0, 10), (idle("!"), idle(".")));
+    clock.advance(chrono::Duration::seconds(10));
+    sprinkler.handle_other_oom(meters.clone(), &shiplift::rep::Actor { id: String::from("4c01db0b339c"), attributes: HashMap::new() });
+    assert_eq!((10, 0), (idle("!"), idle(".")));
+}
+
+#[test]
+fn test_respawn_fix() {
+    let policy: DockerOOMPolicy = toml::from_str(r#"
+        [[rules]]
+        namespace = "kube-system"
+        action = "ignore"
+
+        [[rules]]
+        namespace = "jhub-*"
+        pod = "jupyter-*"
+        action = "evict"
+
+        [[offenders.escalation]]
+        score = 1.0
+        block = true
+    "#).unwrap();
+    let sprinkler = DockerOOM::build(SprinklerOptions::default()).with_policy(policy);
+    let actor = |namespace: &str, pod: &str, extra: &[(&str, &str)]| {
+        let mut attributes: HashMap<String, String> = extra.iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+        attributes.insert(String::from("io.kubernetes.pod.namespace"), String::from(namespace));
+        attributes.insert(String::from("io.kubernetes.pod.name"), String::from(pod));
+        shiplift::rep::Actor { id: String::from("4c01db0b339c"), attributes }
+    };
+    let hub = actor("jhub-prod", "hub-7d9f8b6c5-x2x4z", &[]);
+    let dns = actor("kube-system", "coredns-5c98db65d4-8xjx2", &[]);
+    let alex = actor("jhub-prod", "jupyter-alex", &[("annotation.hub.jupyter.org/username", "alex")]);
+    for pod in &[&hub, &dns, &alex] {
+        let offender = sprinkler.policy.offenders.offender_of(&pod.attributes);
+        sprinkler.offenders.lock().unwrap().offend(&offender);
+    }
+    let fix = |actor: &shiplift::rep::Actor| sprinkler.respawn_fix(actor)
+        .map(|(fix, offender)| (fix.action, fix.stop_grace, offender));
+
+    // A blocked offender is stopped right away, as its rule says
+    assert_eq!(Some((None, Some(0), String::from("jhub-prod/hub"))), fix(&hub));
+    assert_eq!(Some((Some(RuleAction::Evict), Some(0), String::from("jhub-prod/alex"))), fix(&alex));
+    // but not when it is exempted by a rule or an annotation
+    assert_eq!(None, fix(&dns));
+    let exempted = actor("jhub-prod", "hub-7d9f8b6c5-x2x4z", &[("annotation.sprinkler.k8s/ignore", "true")]);
+    assert_eq!(None, fix(&exempted));
+    // nor is the sandbox of its pod
+    let sandbox = actor("jhub-prod", "hub-7d9f8b6c5-x2x4z", &[("io.kubernetes.docker.type", "podsandbox")]);
+    assert_eq!(None, fix(&sandbox));
+    // and others are let be
+    assert_eq!(None, fix(&actor("jhub-prod", "proxy-6b8c9d7f5-q4w2e", &[])));
+}
 
 impl Sprinkler for DockerOOM {
     fn build(options: SprinklerOptions) -> Self {
         DockerOOM {
             options: Arc::new(options),
-            _deactivate: Arc::new(Mutex::new(false))
+            policy: Arc::new(Default::default()),
+            kubernetes: Arc::new(None),
+            limiter: Arc::new(Mutex::new(Limiter::new(Default::default()))),
+            offenders: Arc::new(Mutex::new(Offenders::new(Default::default()))),
+            restricted: Arc::new(Mutex::new(HashMap::new())),
+            fixes: Arc::new(Mutex::new(None)),
+            _deactivate: Arc::new(Mutex::new(None))
         }
     }
 
@@ -308,31 +626,102 @@ impl Sprinkler for DockerOOM {
 
     fn activate_master(&self) -> ActivationResult {
         let (tx, rx) = futures::sync::mpsc::channel::<Message>(512);
+        let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+        *self._deactivate.lock().unwrap() = Some(stop_tx);
         tokio::spawn({
             rx.for_each({ let clone = self.clone(); move |message| {
-                info!(
-                    "sprinkler[{}] (DockerOOM) {}:\n{}",
-                    clone.id(), clone.hostname(), &message.body
-                );
+                status::contact(clone.id());
+                match Report::decode(&message.body) {
+                    Ok(report) => {
+                        let kind = match &report.event {
+                            Event::Anomaly { .. } => "anomaly",
+                            Event::Remediation { .. } => "remediation",
+                            Event::Monitor { .. } => "monitor",
+                            Event::Offender { .. } => "offender"
+                        };
+                        metrics::inc("sprinkler_reports_received_total", &[("host", &report.host), ("kind", kind)]);
+                        match &report.event {
+                            Event::Offender { block: true, .. } => warn!(
+                                "sprinkler[{}] (DockerOOM) {}: {}",
+                                clone.id(), clone.hostname(), &report.event
+                            ),
+                            _ => info!(
+                                "sprinkler[{}] (DockerOOM) {}: {}",
+                                clone.id(), clone.hostname(), &report.event
+                            )
+                        }
+                        store::record(&report);
+                        status::record(clone.id(), &report);
+                        if let Some(alert) = incidents::correlate(&report) {
+                            alerts::dispatch(&alert);
+                        }
+                    }
+                    Err(e) => {
+                        metrics::inc("sprinkler_report_decode_errors_total", &[]);
+                        warn!(
+                            "sprinkler[{}] (DockerOOM) {}: {}:\n{}",
+                            clone.id(), clone.hostname(), e, &message.body
+                        )
+                    }
+                }
                 Ok(())
             }})
+            .select(stop_rx.map_err(|_| ()))
+            .then(|_| Ok(()))
         });
         ActivationResult::AsyncMonitor(tx)
     }
 
     fn activate_agent(&self) {
+        let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+        *self._deactivate.lock().unwrap() = Some(stop_tx);
+        let (fix_tx, fix_rx) = futures::sync::mpsc::unbounded::<Fix>();
+        *self.fixes.lock().unwrap() = Some(fix_tx);
+
+        // Fixes run until the queue is closed and drained
+        let remediation = fix_rx
+            .map({ let clone = self.clone(); move |fix| clone.remediate(fix).then(|_| Ok(())) })
+            .buffer_unordered(FIX_CONCURRENCY)
+            .for_each(|_| Ok(()))
+            .then({ let clone = self.clone(); move |_| {
+                info!("sprinkler[{}] (DockerOOM) {} => deactivated", clone.id(), clone.hostname());
+                clone.report(Event::Monitor { status: MonitorStatus::Deactivated, reason: None });
+                Ok(())
+            }});
+        tokio::spawn(remediation);
+
         let clone = self.clone();
-        let docker = shiplift::Docker::new();
-        let mut meters = HashMap::new();
-        meters.insert(String::from("!"), Default::default()); // Other types of message flooding
-        meters.insert(String::from("."), Mutex::new((
-            Default::default(), // Unidentified OOM
-            FrequencyDivider { interval: 15, ..Default::default() }
-        )));
-        let meters: MeterSet = Arc::new(RwLock::new(meters));
-        let monitor = docker
-            .events(&Default::default()) // Stream and filter all docker events
-            .for_each({ let meters = meters.clone(); move |e| {
+        let meters: MeterSet = Arc::new(RwLock::new(shared_meters(&self.policy, SystemClock)));
+        metrics::collect(format!("docker_oom/{}", self.id()), {
+            let (id, meters, oom_rate) = (self.id().to_string(), meters.clone(), self.policy.oom_rate);
+            move |registry| {
+                registry.set("sprinkler_oom_rate_threshold_hz", &[("sprinkler", &id)], oom_rate as f64);
+                let meters = meters.read().unwrap();
+                registry.set("sprinkler_meters", &[("sprinkler", &id)], meters.len() as f64);
+                let mut states: HashMap<String, usize> = HashMap::new();
+                for (name, meter) in meters.iter() {
+                    let meter = meter.lock().unwrap();
+                    registry.set("sprinkler_meter_rate_hz", &[("sprinkler", &id), ("meter", name)], meter.rate.read() as f64);
+                    let state = format!("{:?}", meter.state); // e.g. Fixing(2)
+                    *states.entry(String::from(state.split('(').next().unwrap_or_default())).or_insert(0) += 1;
+                }
+                for (state, n) in states {
+                    registry.set("sprinkler_anomalies", &[("sprinkler", &id), ("state", &state)], n as f64);
+                }
+            }
+        });
+        let stop_rx = stop_rx.shared();
+        let gc = tokio::timer::Interval::new_interval(METER_GC_INTERVAL)
+            .for_each({ let clone = self.clone(); let meters = meters.clone(); move |_| {
+                clone.collect_meters(&meters);
+                Ok(())
+            }})
+            .map_err(|e| error!("{}", e))
+            .select(stop_rx.clone().then(|_| Ok(())))
+            .then(|_| Ok(()));
+        tokio::spawn(gc);
+        let monitor = self
+            .supervise_events(move |e| { // Stream and filter all docker events
                 if e.typ == "container" && e.action == "oom" {
                     if let Some(pod_name) = e.actor.attributes.get("io.kubernetes.pod.name") {
                         trace!("handle_anticipated_oom(.. {} ..)", pod_name);
@@ -343,275 +732,570 @@ impl Sprinkler for DockerOOM {
                         clone.handle_other_oom(meters.clone(), &e.actor);
                     }
                 }
-                else { clone.handle_other_panic(meters.clone()); }
-                Ok(())
-            }})
-            .map_err(|e| error!("{}", e));
+                else {
+                    if e.typ == "container" && e.action == "start" {
+                        clone.block_respawn(&e.actor);
+                    }
+                    clone.handle_other_panic(meters.clone());
+                }
+            })
+            .select(stop_rx.then(|_| Ok(())))
+            .then(|_| Ok(()));
         tokio::spawn(monitor);
     }
 
     fn deactivate(&self) {
-        *self._deactivate.lock().unwrap() = true;
+        if let Some(stop) = self._deactivate.lock().unwrap().take() {
+            let _ = stop.send(()); // Cancel the monitor
+        }
+        metrics::stop_collecting(&format!("docker_oom/{}", self.id()));
+        metrics::remove("sprinkler_docker_event_lag_seconds", &[("sprinkler", &self.id().to_string())]);
+        self.fixes.lock().unwrap().take(); // Close the queue of fixes
+        let containers = self.restricted.lock().unwrap().keys().cloned().collect::<Vec<String>>();
+        for container in containers {
+            self.lift(&container, None);
+        }
+    }
+}
+
+/// Position in the docker event stream up to which events have been handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EventCursor {
+    time: u64,      // Seconds since epoch, for the `since` filter
+    time_nano: u64  // Nanoseconds since epoch, to skip replayed events
+}
+
+impl EventCursor {
+    fn now() -> Self {
+        let time = chrono::Utc::now().timestamp() as u64;
+        EventCursor { time, time_nano: time * 1_000_000_000 }
     }
+
+    /// Move past an event, or return false if it has been handled before
+    fn advance(&mut self, time: u64, time_nano: u64) -> bool {
+        if time_nano <= self.time_nano { return false; }
+        self.time = time;
+        self.time_nano = time_nano;
+        true
+    }
+
+    fn options(&self) -> shiplift::builder::EventsOptions {
+        shiplift::builder::EventsOptions::builder().since(&self.time).build()
+    }
+}
+
+#[test]
+fn test_event_cursor() {
+    let mut cursor = EventCursor { time: 100, time_nano: 100_000_000_000 };
+    assert!(cursor.advance(100, 100_500_000_000));
+    assert!(cursor.advance(101, 101_200_000_000));
+    assert_eq!(EventCursor { time: 101, time_nano: 101_200_000_000 }, cursor);
+
+    // Replay after reconnecting since the 101st second
+    assert!(!cursor.advance(101, 101_100_000_000));
+    assert!(!cursor.advance(101, 101_200_000_000));
+    assert!(cursor.advance(101, 101_300_000_000));
 }
 
+/// Delay before reconnecting to dockerd, which doubles after every fruitless attempt
+const RECONNECT_DELAY_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: std::time::Duration = std::time::Duration::from_secs(60);
+
 impl DockerOOM {
+    /// Replace the default policy
+    pub fn with_policy(mut self, policy: DockerOOMPolicy) -> Self {
+        self.limiter = Arc::new(Mutex::new(Limiter::new(policy.limits.clone())));
+        self.offenders = Arc::new(Mutex::new(Offenders::new(policy.offenders.clone())));
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Fix pods through the Kubernetes API as the policy says
+    pub fn with_kubernetes(mut self, kubernetes: Option<KubeConfig>) -> Self {
+        self.kubernetes = Arc::new(kubernetes);
+        self
+    }
+
+    /// Feed docker events to a handler, reconnecting and resuming whenever the stream breaks
+    fn supervise_events<F>(&self, handler: F) -> impl Future<Item = (), Error = ()>
+        where F: Fn(shiplift::rep::Event) + Send + Sync + 'static {
+        let handler = Arc::new(handler);
+        let cursor = Arc::new(Mutex::new(EventCursor::now()));
+        let degraded = Arc::new(Mutex::new(false));
+        let clone = self.clone();
+        future::loop_fn(RECONNECT_DELAY_MIN, move |delay| {
+            let received = Arc::new(Mutex::new(false));
+            let options = cursor.lock().unwrap().options();
+            let events = shiplift::Docker::new()
+                .events(&options)
+                .for_each({
+                    let (handler, cursor, degraded, received, clone) = (handler.clone(), cursor.clone(), degraded.clone(), received.clone(), clone.clone());
+                    move |e| {
+                        *received.lock().unwrap() = true;
+                        if std::mem::replace(&mut *degraded.lock().unwrap(), false) {
+                            info!("sprinkler[{}] (DockerOOM) {} => docker events resumed", clone.id(), clone.hostname());
+                            clone.report(Event::Monitor { status: MonitorStatus::Recovered, reason: None });
+                        }
+                        if cursor.lock().unwrap().advance(e.time, e.time_nano) {
+                            let now = chrono::Utc::now();
+                            let lag = now.timestamp() as f64 + f64::from(now.timestamp_subsec_nanos()) / 1e9 - e.time_nano as f64 / 1e9;
+                            metrics::set("sprinkler_docker_event_lag_seconds", &[("sprinkler", &clone.id().to_string())], lag);
+                            handler(e);
+                        }
+                        Ok(())
+                    }
+                });
+            let (degraded, clone) = (degraded.clone(), clone.clone());
+            events.then(move |result| {
+                let reason = match result {
+                    Ok(()) => String::from("docker events ended"),
+                    Err(e) => format!("docker events failed: {}", e)
+                };
+                let next_delay = if *received.lock().unwrap() { RECONNECT_DELAY_MIN }
+                    else { std::cmp::min(delay * 2, RECONNECT_DELAY_MAX) };
+                warn!("sprinkler[{}] (DockerOOM) {}, reconnecting in {:?}", clone.id(), &reason, next_delay);
+                if !std::mem::replace(&mut *degraded.lock().unwrap(), true) {
+                    clone.report(Event::Monitor { status: MonitorStatus::Degraded, reason: Some(reason) });
+                }
+                tokio::timer::Delay::new(std::time::Instant::now() + next_delay)
+                    .then(move |_| Ok(future::Loop::Continue(next_delay)))
+            })
+        })
+    }
+
+    fn collect_meters(&self, meters: &MeterSet) {
+        let ttl = chrono::Duration::seconds(self.policy.meter_ttl as i64);
+        let mut meters = meters.write().unwrap();
+        collect_meters(&mut meters, ttl);
+        debug!("sprinkler[{}] (DockerOOM) {} meters in use", self.id(), meters.len());
+    }
+
+    /// Send the master a report
+    fn report(&self, event: Event) {
+        let id = self.id().to_string();
+        match &event {
+            Event::Anomaly { transition, .. } =>
+                metrics::inc("sprinkler_anomaly_transitions_total", &[("sprinkler", &id), ("transition", &format!("{:?}", transition))]),
+            Event::Remediation { action, outcome, .. } => {
+                let outcome = match outcome {
+                    Outcome::Succeeded => "succeeded",
+                    Outcome::Failed(_) => "failed",
+                    Outcome::DryRun => "dry_run",
+                    Outcome::Suppressed(_) => "suppressed"
+                };
+                let action = format!("{:?}", action).to_lowercase();
+                metrics::inc("sprinkler_remediations_total", &[("sprinkler", &id), ("action", &action), ("outcome", outcome)]);
+            }
+            _ => ()
+        }
+        Notification {
+            from: self.id(),
+            to_addr: self.options.master_addr.clone(),
+            report: Report::new(SprinklerType::DockerOOM, self.hostname(), event)
+        }.send();
+    }
+
     fn handle_anticipated_oom<'a>(&self, meters: MeterSet, pod_name: &'a str, actor: &'a shiplift::rep::Actor) {
+        let decision = self.policy.decide(&actor.attributes);
+        if decision.action == Some(RuleAction::Ignore) {
+            trace!("handle_anticipated_oom(.. {} ..) >> ignored", pod_name);
+            return;
+        }
         let need_new_meter = !meters.read().unwrap().contains_key(pod_name);
         if need_new_meter {
-            let meter = (
-                EventRateMeter { count: 1, state: Anomaly::Fixing(1), ..Default::default() }, // Jump to fixing(1) state
-                FrequencyDivider { interval: 5, ..Default::default() } // Divide event frequency by 5
-            );
-            meters.write().unwrap().insert(String::from(pod_name), Mutex::new(meter));
+            let mut meter = Meter::new(self.policy.oom_meter.build(), decision.divider); // Divide event frequency
+            meter.rate.tick();
+            meter.state = Anomaly::Fixing(1); // Jump to fixing(1) state
+            let mut meters = meters.write().unwrap();
+            meters.insert(String::from(pod_name), Mutex::new(meter));
         }
         else {
             let meters = meters.read().unwrap();
             let mut meter = meters[pod_name].lock().unwrap();
-            meter.0.tick();
-            if meter.0.read() > 10.0 { // Event rate > 10 Hz
+            meter.rate.tick();
+            if meter.rate.read() > decision.oom_rate {
                 trace!("handle_anticipated_oom(.. {} ..) >> event rate = high", pod_name);
-                let transition = meter.0.state.escalate(20); // 20 retries till declaring out-of-control
-                meter.1.tick();
-                if meter.1.read() { // Hit handling schedule
-                    if transition == AnomalyTransition::Fixing {
-                        self.fix_it(actor.id.clone());
+                let transition = meter.state.escalate(decision.max_retry); // Retries till declaring out-of-control
+                meter.divider.tick();
+                if meter.divider.read() { // Hit handling schedule
+                    if transition == AnomalyTransition::Fixing && decision.action != Some(RuleAction::Notify) {
+                        self.fix_it(actor, decision.action);
                     }
                     if transition.is_important() {
                         // Reachable states: Positive, Fixing(n), Out-of-control
                         // Reachable transitions: Occurred, Giveup
-                        let mut data_ = HashMap::new();
-                        data_.insert(String::from("msg"), format!("DockerOOM {:?}", &transition));
-                        data_.insert(
-                            String::from("io.kubernetes.pod.namespace"),
-                            actor.attributes.get("io.kubernetes.pod.namespace").unwrap().clone());
-                        data_.insert(
-                            String::from("io.kubernetes.pod.name"),
-                            actor.attributes.get("io.kubernetes.pod.name").unwrap().clone());
-                        data_.insert(
-                            String::from("io.kubernetes.pod.uid"),
-                            actor.attributes.get("io.kubernetes.pod.uid").unwrap().clone());
-                        Notification{
-                            from: self.id(),
-                            to_addr: self.options.master_addr.clone(),
-                            data: data_
-                        }.send();
+                        self.report(Event::Anomaly {
+                            subject: subject_of(actor),
+                            transition: transition.into()
+                        });
                     }
-                    meter.0.state >>= transition;
+                    meter.state >>= transition;
                 }
             }
             else {
-                let transition = meter.0.state.diminish();
-                let mut data_ = HashMap::new();
-                data_.insert(String::from("msg"), format!("DockerOOM {:?}", &transition));
-                data_.insert(
-                    String::from("io.kubernetes.pod.namespace"),
-                    actor.attributes.get("io.kubernetes.pod.namespace").unwrap().clone());
-                data_.insert(
-                    String::from("io.kubernetes.pod.name"),
-                    actor.attributes.get("io.kubernetes.pod.name").unwrap().clone());
-                data_.insert(
-                    String::from("io.kubernetes.pod.uid"),
-                    actor.attributes.get("io.kubernetes.pod.uid").unwrap().clone());
+                let transition = meter.state.diminish();
                 match transition {
-                    AnomalyTransition::Disappeared => {
-                        Notification{
-                            from: self.id(),
-                            to_addr: self.options.master_addr.clone(),
-                            data: data_
-                        }.send();
-                    }
-                    AnomalyTransition::Fixed => {
-                        Notification{
-                            from: self.id(),
-                            to_addr: self.options.master_addr.clone(),
-                            data: data_
-                        }.send();
+                    AnomalyTransition::Disappeared | AnomalyTransition::Fixed => {
+                        self.report(Event::Anomaly {
+                            subject: subject_of(actor),
+                            transition: transition.into()
+                        });
                     }
                     _ => {}
                 }
-                meter.0.state >>= transition;
+                meter.state >>= transition;
             }
         }
     }
 
     fn handle_other_oom<'a>(&self, meters: MeterSet, actor: &'a shiplift::rep::Actor) {
+        let decision = self.policy.decide(&actor.attributes);
+        if decision.action == Some(RuleAction::Ignore) {
+            trace!("handle_other_oom(..) >> ignored");
+            return;
+        }
         let meters = meters.read().unwrap();
         let mut meter = meters["."].lock().unwrap();
-        meter.0.tick();
-        if meter.0.read() > 10.0 {
+        meter.rate.tick();
+        if meter.rate.read() > decision.oom_rate {
             trace!("handle_other_oom(..) >> event rate = high");
-            let transition = meter.0.state.escalate(20);
-            meter.1.tick();
-            if meter.1.read() {
-                if transition == AnomalyTransition::Fixing {
-                    self.fix_it(actor.id.clone());
+            let transition = meter.state.escalate(decision.max_retry);
+            meter.divider.tick();
+            if meter.divider.read() {
+                if transition == AnomalyTransition::Fixing && decision.action != Some(RuleAction::Notify) {
+                    self.fix_it(actor, decision.action);
                 }
                 if transition.is_important() {
                     // Reachable states: Positive, Fixing(n), Out-of-control
                     // Reachable transitions: Occurred, Giveup
-                    let mut data_ = HashMap::new();
-                    data_.insert(String::from("msg"), format!("DockerOOM {:?}", &transition));
-                    data_.insert(
-                        String::from("name"),
-                        actor.attributes.get("name").unwrap().clone());
-                    Notification{
-                        from: self.id(),
-                        to_addr: self.options.master_addr.clone(),
-                        data: data_
-                    }.send();
+                    self.report(Event::Anomaly {
+                        subject: subject_of(actor),
+                        transition: transition.into()
+                    });
                 }
-                meter.0.state >>= transition;
+                meter.state >>= transition;
             }
         }
         else {
-            let transition = meter.0.state.diminish();
+            let transition = meter.state.diminish();
             if transition.is_important() {
                 // Reachable states: Negative
                 // Reachable transitions: Fixed, Disappeared
-                let mut data_ = HashMap::new();
-                data_.insert(String::from("msg"), format!("DockerOOM {:?}", &transition));
-                data_.insert(
-                    String::from("name"),
-                    actor.attributes.get("name").unwrap().clone());
-                Notification{
-                    from: self.id(),
-                    to_addr: self.options.master_addr.clone(),
-                    data: data_
-                }.send();
+                self.report(Event::Anomaly {
+                    subject: subject_of(actor),
+                    transition: transition.into()
+                });
             }
-            meter.0.state >>= transition;
+            meter.state >>= transition;
         }
     }
 
     fn handle_other_panic(&self, meters: MeterSet) {
         let meters = meters.read().unwrap();
-        let mut meter = meters["."].lock().unwrap();
-        meter.0.tick();
-        if meter.0.read() > 70.0 {
-            if let Some(transition) = meter.0.state >> Anomaly::Positive {
+        let mut meter = meters["!"].lock().unwrap();
+        meter.rate.tick();
+        if meter.rate.read() > self.policy.panic_rate {
+            if let Some(transition) = meter.state >> Anomaly::Positive {
                 if transition.is_important() {
                     // Reachable states: Positive, Fixing(n), Out-of-control
                     // Reachable transitions: Occurred, Giveup
-                    let mut data_ = HashMap::new();
-                    data_.insert(String::from("msg"), format!("Docker Panic"));
-                    Notification{
-                        from: self.id(),
-                        to_addr: self.options.master_addr.clone(),
-                        data: data_
-                    }.send();
+                    self.report(Event::Anomaly { subject: Subject::Docker, transition: transition.into() });
                 }
-                meter.0.state = Anomaly::Positive;
+                meter.state = Anomaly::Positive;
             }
         }
         else {
-            let transition = meter.0.state.diminish();
+            let transition = meter.state.diminish();
             if transition.is_important() {
                 // Reachable states: Negative
                 // Reachable transitions: Fixed, Disappeared
-                let mut data_ = HashMap::new();
-                data_.insert(String::from("msg"), format!("Docker Panic"));
-                Notification{
-                    from: self.id(),
-                    to_addr: self.options.master_addr.clone(),
-                    data: data_
-                }.send();
+                self.report(Event::Anomaly { subject: Subject::Docker, transition: transition.into() });
             }
-            meter.0.state >>= transition;
+            meter.state >>= transition;
         }
     }
 
-    fn fix_it(&self, id: String) {
-        trace!("fix_it({})", id);
-        let docker = shiplift::Docker::new();
-        let container = shiplift::Container::new(&docker, &id);
-        let fut_kill = container.kill(None)  // Should send SIGKILL by default
-            .map_err({
-                let container_id = id.clone();
-                move |_| {
-                    error!("Unable to kill a contianer: {}", &container_id);
-                }
-            });
-        let fut_rm = {
-            let container_id = id.clone();
-            move |_| {
-                let docker = shiplift::Docker::new();
-                let container = shiplift::Container::new(&docker, &container_id);
-                let rm_options = shiplift::builder::RmContainerOptionsBuilder::default().force(true).build();
-                container.remove(rm_options)
-                    .map_err({
-                        let container_id = container_id.clone();
-                        move |_| {
-                            error!("Unable to remove a contianer: {}", &container_id);
-                        }
-                    })
+    fn fix_it(&self, actor: &shiplift::rep::Actor, action: Option<RuleAction>) {
+        trace!("fix_it({})", &actor.id);
+        let mut fix = Fix { container: actor.id.clone(), subject: subject_of(actor), action, stop_grace: None };
+        if !self.admit(&fix) { return; }
+        let offender = self.policy.offenders.offender_of(&actor.attributes);
+        let standing = self.offenders.lock().unwrap().offend(&offender);
+        if let Some(escalation) = &standing.escalation {
+            fix.stop_grace = escalation.stop_grace;
+            if standing.escalated {
+                warn!(
+                    "sprinkler[{}] (DockerOOM) {} is a repeat offender (score {:.1}) as {}",
+                    self.id(), &fix.subject, standing.score, &offender);
+                self.report(Event::Offender {
+                    subject: fix.subject.clone(),
+                    offender,
+                    score: standing.score,
+                    block: escalation.block
+                });
+            }
+        }
+        self.enqueue(fix);
+    }
+
+    /// Stop a container that has just started if its offender is blocked
+    fn block_respawn(&self, actor: &shiplift::rep::Actor) {
+        if let Some((fix, offender)) = self.respawn_fix(actor) {
+            warn!("sprinkler[{}] (DockerOOM) {} is blocked as {}, stopping {}", self.id(), &fix.subject, &offender, &fix.container);
+            if self.admit(&fix) {
+                self.enqueue(fix);
             }
+        }
+    }
+
+    /// The fix for a container that has just started, if the rules let it be made and its offender is blocked
+    fn respawn_fix(&self, actor: &shiplift::rep::Actor) -> Option<(Fix, String)> {
+        // Pod sandboxes hold the namespaces of a pod, stopping them is the kubelet's business
+        if actor.attributes.get("io.kubernetes.docker.type").map_or(false, |t| t == "podsandbox") {
+            return None;
+        }
+        let decision = self.policy.decide(&actor.attributes);
+        match decision.action {
+            Some(RuleAction::Ignore) | Some(RuleAction::Notify) => return None,
+            _ => ()
+        }
+        let offender = self.policy.offenders.offender_of(&actor.attributes);
+        let escalation = self.offenders.lock().unwrap().blocked(&offender)?;
+        let fix = Fix {
+            container: actor.id.clone(),
+            subject: subject_of(actor),
+            action: decision.action,
+            stop_grace: Some(escalation.stop_grace.unwrap_or(0))
         };
-        let fut_notify = {
-            let container_id = id.clone();
-            let sprinkler_id = self.id();
-            let master_addr = self.options.master_addr.clone();
-            move |_| {
-                let mut data_ = HashMap::new();
-                data_.insert(String::from("msg"), format!("Killed & Removed {}", &container_id));
-                Notification {
-                    from: sprinkler_id,
-                    to_addr: master_addr,
-                    data: data_
+        Some((fix, offender))
+    }
+
+    /// Check a fix against dry runs and the limits, reporting it if it is not to be made
+    fn admit(&self, fix: &Fix) -> bool {
+        if self.policy.is_dry_run(&fix.subject) {
+            info!("sprinkler[{}] (DockerOOM) dry run, not fixing {} of {}", self.id(), &fix.container, &fix.subject);
+            self.report_plan(fix, Outcome::DryRun);
+            return false;
+        }
+        let verdict = self.limiter.lock().unwrap().acquire();
+        match verdict {
+            Verdict::Allowed => true,
+            Verdict::Tripped => {
+                self.trip();
+                true
+            }
+            Verdict::RateLimited | Verdict::Open => {
+                let reason = if verdict == Verdict::Open { "circuit breaker open" } else { "rate limited" };
+                warn!("sprinkler[{}] (DockerOOM) {}, not fixing {} of {}", self.id(), reason, &fix.container, &fix.subject);
+                self.report_plan(fix, Outcome::Suppressed(String::from(reason)));
+                false
+            }
+        }
+    }
+
+    fn enqueue(&self, fix: Fix) {
+        let container = fix.container.clone();
+        match &*self.fixes.lock().unwrap() {
+            Some(fixes) if fixes.unbounded_send(fix).is_ok() => (),
+            _ => warn!("sprinkler[{}] (DockerOOM) is deactivated, not fixing {}", self.id(), &container)
+        }
+    }
+
+    /// Report the steps of a fix that is not being made
+    fn report_plan(&self, fix: &Fix, outcome: Outcome) {
+        for action in self.policy.remediation.plan(fix) {
+            self.report(Event::Remediation {
+                subject: fix.subject.clone(),
+                container: fix.container.clone(),
+                action,
+                outcome: outcome.clone()
+            });
+        }
+    }
+
+    /// Switch to notify-only until the breaker cools down
+    fn trip(&self) {
+        let limits = &self.policy.limits;
+        let reason = format!(
+            "{} fixes within {}s, notifying only for {}s",
+            limits.breaker_threshold, limits.breaker_window, limits.breaker_cooldown);
+        error!("sprinkler[{}] (DockerOOM) {} => circuit breaker tripped: {}", self.id(), self.hostname(), &reason);
+        self.report(Event::Monitor { status: MonitorStatus::BreakerTripped, reason: Some(reason) });
+        let cooldown = self.limiter.lock().unwrap().cooldown();
+        self.reset_after(cooldown);
+    }
+
+    /// Close the breaker once it has cooled down, checking again if the timer fires early
+    fn reset_after(&self, delay: std::time::Duration) {
+        let clone = self.clone();
+        tokio::spawn(tokio::timer::Delay::new(std::time::Instant::now() + delay).then(move |_| {
+            let reset = {
+                let mut limiter = clone.limiter.lock().unwrap();
+                if limiter.reset_if_due() { Ok(()) } else { Err(limiter.remaining()) }
+            };
+            match reset {
+                Ok(()) => {
+                    info!("sprinkler[{}] (DockerOOM) {} => circuit breaker reset", clone.id(), clone.hostname());
+                    clone.report(Event::Monitor { status: MonitorStatus::BreakerReset, reason: None });
                 }
+                Err(Some(remaining)) => clone.reset_after(remaining),
+                Err(None) => () // Closed in the meantime
             }
+            Ok(())
+        }));
+    }
+
+    /// Restrict a container or take it down by its pod if the policy says so, or else through docker
+    fn remediate(&self, fix: Fix) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if let Some(restriction) = self.policy.remediation.restriction(&fix) {
+            self.restrict(fix, restriction);
+            return Box::new(future::ok(()));
+        }
+        let pod = match (&fix.subject, self.policy.remediation.pod_action(&fix)) {
+            (Subject::Pod(pod), Some(action)) => match &*self.kubernetes {
+                Some(kubernetes) => Some((pod.clone(), action, kubernetes)),
+                None => {
+                    warn!("sprinkler[{}] (DockerOOM) has no Kubernetes API server to fix {} with", self.id(), &fix.subject);
+                    None
+                }
+            },
+            _ => None
         };
-        let fut_fix = fut_kill.then(fut_rm).and_then(fut_notify);
-        tokio::spawn(fut_fix);
-    }
-}
-
-const RETRY_DELAY: u64 = 20;
-
-/// An asynchronous message
-#[derive(Clone, Debug)]
-struct Notification {
-    /// Raw data in the notification
-    /// Fields: message, io.kubernetes.pod.{namespace,name,uid}
-    data: HashMap<String, String>,
-
-    from: usize,
-    to_addr: String,
-}
-
-impl Future for Notification {
-    type Item = ();
-    type Error = ();
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        debug!("Trying to connect to {}", &self.to_addr);
-    
-        if let Ok(socket) = std::net::TcpStream::connect(&self.to_addr) {
-            let mut tlsbuilder = native_tls::TlsConnector::builder();
-            tlsbuilder.add_root_certificate(native_tls::Certificate::from_pem(include_bytes!("/etc/sprinkler.conf.d/master.crt")).unwrap());
-            let connector = tlsbuilder.build().expect("failed to build a TLS connector");
-            let mut stream = connector.connect(&&self.to_addr.split(":").take(1).collect::<Vec<&str>>()[0], socket).expect("failed to establish a TLS stream");
-            let serialized = String::from(self.data.iter().map(|(k, v)| format!("{} = {}", k, v)).collect::<Vec<String>>().join("\n"));
-            let buf = sprinkler_api::compose_message(self.from, serialized);
-            if let Err(e) = stream.write_all(&buf) {
-                debug!("Failed to send the master thread a message: {}", e);
-                thread::sleep(std::time::Duration::from_secs(RETRY_DELAY));
-                Ok(Async::NotReady)
+        match pod {
+            Some((pod, action, kubernetes)) => {
+                let grace = self.policy.remediation.stop_grace(&fix);
+                let request = future::result(kube::Client::load(kubernetes).map_err(|e| e.to_string()))
+                    .and_then(move |client| match action {
+                        Action::Evict => future::Either::A(client.evict(&pod, grace)),
+                        _ => future::Either::B(client.delete(&pod, grace))
+                    });
+                Box::new(self.step(&fix, action, request).map(|_| ()))
             }
-            else {
-                Ok(Async::Ready(()))
+            None => Box::new(self.remediate_container(fix))
+        }
+    }
+
+    /// Restrict a container through its cgroups until the hold expires
+    fn restrict(&self, fix: Fix, restriction: &Restriction) {
+        let (action, lift_action) = match restriction {
+            Restriction::Freeze => (Action::Freeze, Action::Thaw),
+            Restriction::Throttle { .. } => (Action::Throttle, Action::Unthrottle)
+        };
+        if self.restricted.lock().unwrap().contains_key(&fix.container) {
+            debug!("sprinkler[{}] (DockerOOM) {} is restricted already", self.id(), &fix.container);
+            return;
+        }
+        let result = Cgroups::open(&self.policy.remediation.cgroup_root)
+            .and_then(|cgroups| cgroups.restrict(&fix.container, restriction));
+        let saved = match result {
+            Ok(saved) => saved,
+            Err(e) => {
+                error!("sprinkler[{}] (DockerOOM) {}: unable to restrict: {}", self.id(), &fix.container, e);
+                self.report_step(&fix, action, Err(format!("unable to restrict: {}", e)));
+                return;
             }
+        };
+        self.report_step(&fix, action, Ok(()));
+        let since = std::time::Instant::now();
+        let Fix { container, subject, .. } = fix;
+        self.restricted.lock().unwrap().insert(container.clone(), Restricted { subject, action: lift_action, saved, since });
+        let hold = std::time::Duration::from_secs(self.policy.remediation.hold);
+        let clone = self.clone();
+        tokio::spawn(tokio::timer::Delay::new(since + hold).then(move |_| {
+            clone.lift(&container, Some(since));
+            Ok(())
+        }));
+    }
+
+    /// Lift the restriction of a container, unless it is gone or not the one made `since` then
+    fn lift(&self, container: &str, since: Option<std::time::Instant>) {
+        let restricted = {
+            let mut restricted = self.restricted.lock().unwrap();
+            match restricted.get(container) {
+                Some(r) if since.map_or(true, |since| since == r.since) => restricted.remove(container).unwrap(),
+                _ => return
+            }
+        };
+        let fix = Fix { container: String::from(container), subject: restricted.subject, action: None, stop_grace: None };
+        let result = restricted.saved.restore().map_err(|e| format!("unable to lift the restriction: {}", e));
+        if let Err(reason) = &result {
+            error!("sprinkler[{}] (DockerOOM) {}: {}", self.id(), container, reason);
         }
-        else {
-            debug!("Connection error, will retry after {} seconds.", RETRY_DELAY);
-            thread::sleep(std::time::Duration::from_secs(RETRY_DELAY));
-            Ok(Async::NotReady)
+        self.report_step(&fix, restricted.action, result);
+    }
+
+    /// Take a container down following the remediation ladder
+    fn remediate_container(&self, fix: Fix) -> impl Future<Item = (), Error = ()> {
+        let ladder = self.policy.remediation.clone();
+        let id = fix.container.clone();
+        let stop_grace = ladder.stop_grace(&fix);
+        let stopped = if stop_grace > 0 {
+            let grace = std::time::Duration::from_secs(stop_grace);
+            let docker = shiplift::Docker::new();
+            let stop = shiplift::Container::new(&docker, &id).stop(Some(grace))
+                .timeout(grace + STOP_TIMEOUT_SLACK)
+                .map_err(|e| match e.into_inner() {
+                    Some(e) => format!("unable to stop: {}", e),
+                    None => String::from("unable to stop: timed out")
+                });
+            future::Either::A(self.step(&fix, Action::Stop, stop))
         }
+        else { future::Either::B(future::ok(false)) };
+        let killed = {
+            let (clone, fix) = (self.clone(), fix.clone());
+            move |stopped| if stopped { future::Either::A(future::ok(true)) } else {
+                let docker = shiplift::Docker::new();
+                let kill = shiplift::Container::new(&docker, &fix.container).kill(None) // Should send SIGKILL by default
+                    .map_err(|e| format!("unable to kill: {}", e));
+                future::Either::B(clone.step(&fix, Action::Kill, kill))
+            }
+        };
+        let removed = {
+            let clone = self.clone();
+            move |_| if !ladder.remove { future::Either::A(future::ok(true)) } else {
+                let docker = shiplift::Docker::new();
+                let rm_options = shiplift::builder::RmContainerOptionsBuilder::default().force(true).build();
+                let remove = shiplift::Container::new(&docker, &fix.container).remove(rm_options)
+                    .map_err(|e| format!("unable to remove: {}", e));
+                future::Either::B(clone.step(&fix, Action::Remove, remove))
+            }
+        };
+        stopped.and_then(killed).and_then(removed).map(|_| ())
+    }
+
+    /// Take a step of the remediation ladder and report how it went
+    fn step<F>(&self, fix: &Fix, action: Action, fut: F) -> impl Future<Item = bool, Error = ()>
+        where F: Future<Item = (), Error = String> {
+        let (clone, fix) = (self.clone(), fix.clone());
+        fut.then(move |result| {
+            if let Err(reason) = &result {
+                error!("sprinkler[{}] (DockerOOM) {}: {}", clone.id(), &fix.container, reason);
+            }
+            let succeeded = result.is_ok();
+            clone.report_step(&fix, action, result);
+            Ok(succeeded)
+        })
+    }
+
+    fn report_step(&self, fix: &Fix, action: Action, result: Result<(), String>) {
+        self.report(Event::Remediation {
+            subject: fix.subject.clone(),
+            container: fix.container.clone(),
+            action,
+            outcome: match result {
+                Ok(()) => Outcome::Succeeded,
+                Err(reason) => Outcome::Failed(reason)
+            }
+        });
     }
 }
 
-impl Notification {
-    pub fn send(self) {
-        tokio::spawn(self);
+/// Identify the owner of a container from its labels
+fn subject_of(actor: &shiplift::rep::Actor) -> Subject {
+    if actor.attributes.contains_key("io.kubernetes.pod.name") {
+        Subject::Pod(PodIdentity::from_labels(&actor.attributes))
+    }
+    else {
+        Subject::Container { name: actor.attributes.get("name").cloned().unwrap_or_default() }
     }
 }
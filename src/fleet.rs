@@ -0,0 +1,205 @@
+use tokio::prelude::*;
+use sprinkler_api::Sprinkler;
+use crate::config::{self, Config, SprinklerSpec};
+
+/// Running sprinklers along with the specifications they were built from
+#[derive(Default)]
+pub struct Fleet {
+    specs: Vec<SprinklerSpec>,
+    sprinklers: Vec<Box<dyn Sprinkler>>
+}
+
+impl Fleet {
+    /// Bring the fleet in line with a configuration
+    ///
+    /// Sprinklers absent from the configuration are deactivated, new ones are passed to `activate`,
+    /// and unchanged ones keep running untouched.
+    pub fn reload<F>(&mut self, config: &Config, activate: F) where F: FnOnce(&Vec<Box<dyn Sprinkler>>) {
+        let specs = self.number(config.specs());
+        let mut running: Vec<Option<Box<dyn Sprinkler>>> = specs.iter().map(|_| None).collect();
+        for (spec, sprinkler) in self.specs.drain(..).zip(self.sprinklers.drain(..)) {
+            match specs.iter().position(|new| *new == spec) {
+                Some(i) => running[i] = Some(sprinkler),
+                None => {
+                    info!("sprinkler[{}] ({:?}) {} => deactivated", spec.id, spec.typ, spec.hostname);
+                    sprinkler.deactivate();
+                }
+            }
+        }
+
+        let added: Vec<Box<dyn Sprinkler>> = specs.iter().zip(running.iter())
+            .filter(|(_, sprinkler)| sprinkler.is_none())
+            .map(|(spec, _)| config::build_sprinkler(spec.clone()))
+            .collect();
+        activate(&added);
+
+        let mut added = added.into_iter();
+        self.sprinklers = running.into_iter()
+            .map(|sprinkler| sprinkler.or_else(|| added.next()).expect("a sprinkler is built for every new spec"))
+            .collect();
+        self.specs = specs;
+    }
+
+    /// Specifications of the running sprinklers in the order of the configuration
+    #[allow(dead_code)] // Only the master shows them
+    pub fn specs(&self) -> &[SprinklerSpec] {
+        &self.specs
+    }
+
+    /// Give specifications the ids that sprinklers of the same host and type have had, so that ids
+    /// do not shift as hosts come and go, and number the others after every id in use
+    fn number(&self, mut specs: Vec<SprinklerSpec>) -> Vec<SprinklerSpec> {
+        let mut next = self.specs.iter().map(|spec| spec.id + 1).max().unwrap_or(0);
+        for spec in specs.iter_mut() {
+            spec.id = match self.specs.iter().find(|old| old.typ == spec.typ && old.hostname == spec.hostname) {
+                Some(old) => old.id,
+                None => {
+                    next += 1;
+                    next - 1
+                }
+            };
+        }
+        specs
+    }
+}
+
+/// Reload the configuration from `fname_config` upon every SIGHUP
+pub fn on_sighup<F>(fname_config: String, mut reload: F) -> impl Future<Item = (), Error = ()>
+    where F: FnMut(Config) {
+    tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP)
+        .flatten_stream()
+        .for_each(move |_| {
+            info!("SIGHUP: reloading {}", &fname_config);
+            match Config::load(&fname_config) {
+                Ok(config) => reload(config),
+                Err(e) => error!("{}: {}, keeping the current configuration", &fname_config, e)
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("Unable to listen for SIGHUP: {}", e))
+}
+
+#[cfg(test)]
+fn reload_ids(fleet: &mut Fleet, config: &Config) -> Vec<usize> {
+    let mut activated = Vec::new();
+    fleet.reload(config, |sprinklers| activated = sprinklers.iter().map(|s| s.id()).collect());
+    activated
+}
+
+#[test]
+fn test_fleet_reload() {
+    let mut fleet = Fleet::default();
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    assert_eq!(vec![0, 1], reload_ids(&mut fleet, &config));
+    assert_eq!(Vec::<usize>::new(), reload_ids(&mut fleet, &config));
+
+    // Appending a host leaves the running sprinklers alone
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    assert_eq!(vec![2, 3], reload_ids(&mut fleet, &config));
+    assert_eq!(4, fleet.sprinklers.len());
+
+    // Removing a sprinkler activates nothing
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["CommCheck"]
+    "#).unwrap();
+    assert_eq!(Vec::<usize>::new(), reload_ids(&mut fleet, &config));
+    assert_eq!(3, fleet.sprinklers.len());
+
+    // Retuning a policy restarts only the affected sprinkler
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+        docker_oom = { oom_rate = 5.0 }
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["CommCheck"]
+    "#).unwrap();
+    assert_eq!(vec![1], reload_ids(&mut fleet, &config));
+    assert_eq!(3, fleet.sprinklers.len());
+}
+
+#[test]
+fn test_fleet_reload_ids() {
+    let mut fleet = Fleet::default();
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-3.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    assert_eq!(vec![0, 1, 2, 3, 4, 5], reload_ids(&mut fleet, &config));
+    let ids = |fleet: &Fleet| fleet.sprinklers.iter().map(|s| (s.id(), String::from(s.hostname()))).collect::<Vec<_>>();
+    let before = ids(&fleet);
+
+    // Removing a host in the middle leaves the others running under their ids
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-3.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    assert_eq!(Vec::<usize>::new(), reload_ids(&mut fleet, &config));
+    assert_eq!(vec![&before[0], &before[1], &before[4], &before[5]], ids(&fleet).iter().collect::<Vec<_>>());
+    assert_eq!(vec![0, 1, 4, 5], fleet.specs().iter().map(|spec| spec.id).collect::<Vec<usize>>());
+
+    // Hosts inserted anywhere get ids that have not been in use
+    let config = Config::parse(r#"
+        master_addr = "bridge.dsa.lan:3777"
+
+        [[hosts]]
+        hostname = "k-prod-gpu-1.dsa.lan"
+        sprinklers = ["DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-3.dsa.lan"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    assert_eq!(vec![6], reload_ids(&mut fleet, &config));
+    assert_eq!(vec![6, 0, 1, 4, 5], fleet.specs().iter().map(|spec| spec.id).collect::<Vec<usize>>());
+}
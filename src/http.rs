@@ -0,0 +1,271 @@
+use std::io;
+use std::time::Duration;
+use std::net::{SocketAddr, ToSocketAddrs};
+use serde::Deserialize;
+use tokio::prelude::*;
+
+/// Time allowed for a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parts of an http(s) URL
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// Path along with the query, "/" if absent
+    pub path: String
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, String> {
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            _ => return Err(format!("must start with https:// or http://, got {:?}", url))
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+        let mut parts = authority.splitn(2, ':');
+        let host = String::from(parts.next().unwrap());
+        if host.is_empty() {
+            return Err(format!("has no host: {:?}", url));
+        }
+        let port = match parts.next() {
+            Some(port) => port.parse().map_err(|_| format!("has an invalid port: {:?}", url))?,
+            None => if tls { 443 } else { 80 }
+        };
+        Ok(Url { tls, host, port, path: String::from(path) })
+    }
+}
+
+#[test]
+fn test_url_parse() {
+    assert_eq!(
+        Url { tls: true, host: String::from("hooks.slack.com"), port: 443, path: String::from("/services/T0/B0/x") },
+        Url::parse("https://hooks.slack.com/services/T0/B0/x").unwrap());
+    assert_eq!(
+        Url { tls: false, host: String::from("localhost"), port: 8080, path: String::from("/") },
+        Url::parse("http://localhost:8080").unwrap());
+    assert!(Url::parse("localhost:8080").is_err());
+    assert!(Url::parse("http://:8080/").is_err());
+    assert!(Url::parse("http://localhost:api/").is_err());
+}
+
+/// Resolve a host:port address on a thread set aside for blocking calls, so that a slow resolver
+/// does not hold up the other futures of the worker
+pub fn resolve(addr: String) -> impl Future<Item = SocketAddr, Error = io::Error> {
+    future::poll_fn(move || tokio_threadpool::blocking(|| {
+        addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} resolves to nothing", addr)))
+    }))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(|resolved| resolved)
+}
+
+/// Send a JSON request, taking any status but 2xx as a failure
+///
+/// Servers are verified against `ca_cert`, or the system roots if absent.
+pub fn request(url: &Url, ca_cert: Option<&native_tls::Certificate>, method: &str, path: &str,
+               headers: &[(&str, String)], body: String) -> impl Future<Item = (), Error = String> {
+    let mut buf = format!(
+        "{} {} HTTP/1.0\r\nHost: {}:{}\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        method, path, url.host, url.port, body.len());
+    for (name, value) in headers {
+        buf.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    buf.push_str("\r\n");
+    buf.push_str(&body);
+
+    let url = url.clone();
+    let what = format!("{} {}", method, path);
+    let connector = if url.tls { tls_connector(ca_cert).map(Some) } else { Ok(None) };
+    future::result(connector)
+        .and_then(move |connector| {
+            resolve(format!("{}:{}", url.host, url.port))
+                .and_then(|addr| tokio::net::TcpStream::connect(&addr))
+                .and_then(move |socket| match connector {
+                    Some(connector) => future::Either::A(connector.connect(&url.host, socket)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                        .and_then(move |stream| exchange(stream, buf))),
+                    None => future::Either::B(exchange(socket, buf))
+                })
+                .timeout(REQUEST_TIMEOUT)
+                .map_err(|e| e.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|response| parse_response(&response))
+        .map_err(move |e| format!("{}: {}", what, e))
+}
+
+fn tls_connector(ca_cert: Option<&native_tls::Certificate>) -> io::Result<tokio_tls::TlsConnector> {
+    let mut tlsbuilder = native_tls::TlsConnector::builder();
+    if let Some(cert) = ca_cert {
+        tlsbuilder.add_root_certificate(cert.clone());
+    }
+    Ok(tokio_tls::TlsConnector::from(tlsbuilder.build().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?))
+}
+
+/// Send a request and read the response until the server closes the connection
+fn exchange<S>(stream: S, request: String) -> impl Future<Item = Vec<u8>, Error = io::Error>
+    where S: AsyncRead + AsyncWrite {
+    tokio::io::write_all(stream, request.into_bytes())
+        .and_then(|(stream, _)| tokio::io::read_to_end(stream, Vec::new()))
+        .map(|(_, response)| response)
+}
+
+/// Check the status of a response, explaining failures with the message of a Kubernetes Status object
+fn parse_response(response: &[u8]) -> Result<(), String> {
+    let response = String::from_utf8_lossy(response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let code = status_line.split(' ').nth(1).and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| format!("malformed response {:?}", status_line))?;
+    if code >= 200 && code < 300 { return Ok(()); }
+    #[derive(Deserialize)]
+    struct Status { message: String }
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default();
+    match serde_json::from_str::<Status>(body) {
+        Ok(status) => Err(format!("{} ({})", status.message, code)),
+        Err(_) => Err(String::from(status_line))
+    }
+}
+
+/// Time allowed for a client to send its request and take the response
+const SERVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest request head accepted
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Response of a handler
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response { status: 200, content_type, body }
+    }
+
+    pub fn not_found() -> Response {
+        Response { status: 404, content_type: "text/plain", body: String::from("not found\n") }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let reason = match self.status { 200 => "OK", 400 => "Bad Request", 404 => "Not Found", 405 => "Method Not Allowed", _ => "" };
+        let mut buf = format!(
+            "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status, reason, self.content_type, self.body.len()).into_bytes();
+        buf.extend_from_slice(self.body.as_bytes());
+        buf
+    }
+}
+
+/// Serve GET requests at `addr`, answering each with what `handler` makes of its path
+pub fn serve<F>(addr: &std::net::SocketAddr, handler: F) -> io::Result<impl Future<Item = (), Error = ()>>
+    where F: Fn(&str) -> Response + Send + Sync + 'static {
+    let listener = tokio::net::TcpListener::bind(addr)?;
+    let handler = std::sync::Arc::new(handler);
+    Ok(listener.incoming()
+        .map_err(|e| error!("Unable to accept an HTTP connection: {}", e))
+        .for_each(move |socket| {
+            let handler = handler.clone();
+            let respond = read_request_head(socket)
+                .and_then(move |(socket, head)| {
+                    let mut parts = head.lines().next().unwrap_or_default().split(' ');
+                    let response = match (parts.next(), parts.next()) {
+                        (Some("GET"), Some(path)) => handler(path.split('?').next().unwrap_or_default()),
+                        (Some(_), Some(_)) => Response { status: 405, content_type: "text/plain", body: String::from("only GET is supported\n") },
+                        _ => Response { status: 400, content_type: "text/plain", body: String::from("malformed request\n") }
+                    };
+                    tokio::io::write_all(socket, response.encode())
+                })
+                .and_then(|(socket, _)| tokio::io::shutdown(socket))
+                .timeout(SERVE_TIMEOUT)
+                .map(|_| ())
+                .map_err(|e| debug!("HTTP connection dropped: {:?}", e));
+            tokio::spawn(respond);
+            Ok(())
+        }))
+}
+
+/// Read a request up to the end of its head, which is all GET requests have
+fn read_request_head<S>(socket: S) -> impl Future<Item = (S, String), Error = io::Error> where S: AsyncRead {
+    future::loop_fn((socket, Vec::new()), |(socket, mut head)| {
+        tokio::io::read(socket, vec![0u8; 1024]).and_then(move |(socket, buf, n)| {
+            head.extend_from_slice(&buf[..n]);
+            if head.windows(4).any(|w| w == b"\r\n\r\n") || n == 0 {
+                Ok(future::Loop::Break((socket, String::from_utf8_lossy(&head).into_owned())))
+            }
+            else if head.len() > MAX_REQUEST_HEAD {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"))
+            }
+            else { Ok(future::Loop::Continue((socket, head))) }
+        })
+    })
+}
+
+#[test]
+fn test_serve() {
+    use std::io::{Read, Write};
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap(); // Find a free port
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let server = serve(&addr, |path| match path {
+        "/metrics" => Response::ok("text/plain", String::from("up 1\n")),
+        _ => Response::not_found()
+    }).unwrap();
+    runtime.spawn(server);
+
+    let get = |request: &str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.contains("\r\nContent-Length: 5\r\n"));
+    assert!(response.ends_with("\r\n\r\nup 1\n"));
+    assert!(get("GET /nothing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(get("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.0 405 "));
+}
+
+/// Server that answers one request with a canned response, and hands the request back
+#[cfg(test)]
+pub fn fake_server(response: &'static str) -> (String, std::thread::JoinHandle<String>) {
+    use std::io::{Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop { // Until the headers and the body announced in them have arrived
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text.lines()
+                    .find(|line| line.starts_with("Content-Length: "))
+                    .map(|line| line[16..].parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length || n == 0 { break; }
+            }
+        }
+        stream.write_all(response.as_bytes()).unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    (url, server)
+}
+
+/// Body of a request received by a fake server
+#[cfg(test)]
+pub fn body_of(request: &str) -> serde_json::Value {
+    serde_json::from_str(request.splitn(2, "\r\n\r\n").nth(1).unwrap()).unwrap()
+}
@@ -0,0 +1,180 @@
+use std::sync::Mutex;
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::alerts::{Alert, Severity};
+use crate::meter::{Clock, SystemClock};
+#[cfg(test)]
+use crate::meter::ManualClock;
+use crate::offenders::workload_of;
+use crate::report::{Report, Event, Subject};
+#[cfg(test)]
+use crate::config::SprinklerType;
+#[cfg(test)]
+use crate::report::{PodIdentity, Transition, Action, Outcome, MonitorStatus};
+
+/// How the master merges what several nodes report of the same workload into cluster-level incidents
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorrelationPolicy {
+    /// Seconds since the last report of a workload within which other reports of it join the same incident,
+    /// 0 to alert on every report
+    pub window: u64,
+    /// Nodes that have to report a workload for an incident to be raised
+    pub min_hosts: usize
+}
+
+impl Default for CorrelationPolicy {
+    fn default() -> Self {
+        CorrelationPolicy { window: 300, min_hosts: 2 }
+    }
+}
+
+impl CorrelationPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_hosts < 2 { return Err(format!("min_hosts must be at least 2, got {}", self.min_hosts)); }
+        Ok(())
+    }
+}
+
+/// Namespace, workload and image that reports are correlated by
+type Key = (String, String, String);
+
+/// Reports of a workload from across the cluster
+struct Incident {
+    /// Reporting hosts in the order they joined
+    hosts: Vec<String>,
+    t_last: chrono::DateTime<chrono::Local>, // Last time a report joined
+    raised: Option<Severity>                 // Severity the incident was last raised with
+}
+
+/// Open incidents by workload
+pub struct Correlator<C: Clock = SystemClock> {
+    policy: CorrelationPolicy,
+    incidents: HashMap<Key, Incident>,
+    clock: C
+}
+
+impl Correlator {
+    pub fn new(policy: CorrelationPolicy) -> Self {
+        Correlator::with_clock(policy, SystemClock)
+    }
+}
+
+impl<C: Clock> Correlator<C> {
+    pub fn with_clock(policy: CorrelationPolicy, clock: C) -> Self {
+        Correlator { policy, incidents: HashMap::new(), clock }
+    }
+
+    /// Alert to send for a report, if any
+    ///
+    /// Reports of a workload pass as they are until enough nodes have reported it. Then one alert is raised
+    /// for the cluster, and raised again only when a report is more severe, while the others are suppressed.
+    pub fn correlate(&mut self, report: &Report) -> Option<Alert> {
+        let alert = Alert::from_report(report);
+        let key = match key_of(&report.event) {
+            Some(key) if self.policy.window > 0 => key,
+            _ => return Some(alert)
+        };
+        let now = self.clock.now();
+        let window = chrono::Duration::seconds(self.policy.window as i64);
+        self.incidents.retain(|_, incident| now - incident.t_last <= window);
+
+        let incident = self.incidents.entry(key.clone())
+            .or_insert_with(|| Incident { hosts: Vec::new(), t_last: now, raised: None });
+        incident.t_last = now;
+        if !incident.hosts.contains(&report.host) {
+            incident.hosts.push(report.host.clone());
+            if incident.raised.is_some() {
+                info!("{} now spans {} nodes", describe(&key), incident.hosts.len());
+            }
+        }
+        if incident.hosts.len() < self.policy.min_hosts { return Some(alert); }
+        if incident.raised.map_or(false, |raised| alert.severity <= raised) { return None; }
+
+        incident.raised = Some(alert.severity);
+        Some(Alert {
+            host: String::from("cluster"),
+            hosts: incident.hosts.clone(),
+            summary: format!("{} on {} nodes ({}), latest {}: {}",
+                             describe(&key), incident.hosts.len(), incident.hosts.join(", "), report.host, alert.summary),
+            ..alert
+        })
+    }
+}
+
+/// What a report is correlated by, if it is about a pod
+fn key_of(event: &Event) -> Option<Key> {
+    match event {
+        Event::Anomaly { subject: Subject::Pod(pod), .. } | Event::Remediation { subject: Subject::Pod(pod), .. } =>
+            Some((pod.namespace.clone(), String::from(workload_of(&pod.name)), pod.image.clone())),
+        _ => None
+    }
+}
+
+/// e.g. "workload jhub-prod/hub of image jupyterhub/k8s-hub:0.8.2"
+fn describe((namespace, workload, image): &Key) -> String {
+    if image.is_empty() { format!("workload {}/{}", namespace, workload) }
+    else { format!("workload {}/{} of image {}", namespace, workload, image) }
+}
+
+#[test]
+fn test_correlate() {
+    let clock = ManualClock::default();
+    let mut correlator = Correlator::with_clock(CorrelationPolicy { window: 60, min_hosts: 2 }, clock.clone());
+    let pod = |name: &str| Subject::Pod(PodIdentity {
+        namespace: String::from("jhub-prod"), name: String::from(name), image: String::from("k8s-hub:0.8.2"), ..Default::default()
+    });
+    let report = |host: &str, event: Event| Report::new(SprinklerType::DockerOOM, host, event);
+    let anomaly = |host: &str, name: &str, transition| report(host, Event::Anomaly { subject: pod(name), transition });
+
+    // A single node passes through
+    let alert = correlator.correlate(&anomaly("k-prod-cpu-1", "hub-7d9f8b6c5-x2x4z", Transition::Occurred)).unwrap();
+    assert_eq!("k-prod-cpu-1", alert.host);
+    assert!(alert.hosts.is_empty());
+
+    // Another node raises an incident, after which duplicates are suppressed
+    let alert = correlator.correlate(&anomaly("k-prod-cpu-2", "hub-7d9f8b6c5-qz2wm", Transition::Occurred)).unwrap();
+    assert_eq!("cluster", alert.host);
+    assert_eq!(vec!["k-prod-cpu-1", "k-prod-cpu-2"], alert.hosts);
+    assert_eq!(Severity::Warning, alert.severity);
+    assert_eq!(
+        "workload jhub-prod/hub of image k8s-hub:0.8.2 on 2 nodes (k-prod-cpu-1, k-prod-cpu-2), latest k-prod-cpu-2: pod jhub-prod/hub-7d9f8b6c5-qz2wm Occurred",
+        alert.summary);
+    assert_eq!(None, correlator.correlate(&anomaly("k-prod-cpu-3", "hub-7d9f8b6c5-c2xwk", Transition::Occurred)));
+    assert_eq!(None, correlator.correlate(&report("k-prod-cpu-1", Event::Remediation {
+        subject: pod("hub-7d9f8b6c5-x2x4z"), container: String::from("4c01db0b339c"), action: Action::Kill, outcome: Outcome::Succeeded
+    })));
+
+    // More severe reports raise it again
+    let alert = correlator.correlate(&anomaly("k-prod-cpu-3", "hub-7d9f8b6c5-c2xwk", Transition::GaveUp)).unwrap();
+    assert_eq!((Severity::Critical, 3), (alert.severity, alert.hosts.len()));
+
+    // Other workloads, and reports not about pods, are not part of it
+    assert_eq!("k-prod-cpu-2", correlator.correlate(&anomaly("k-prod-cpu-2", "proxy-5c7d4b8f6b-qz2wm", Transition::Occurred)).unwrap().host);
+    assert!(correlator.correlate(&report("k-prod-cpu-2", Event::Monitor { status: MonitorStatus::Degraded, reason: None })).is_some());
+
+    // Incidents close once the workload has been quiet for the window
+    clock.advance(chrono::Duration::seconds(61));
+    assert_eq!("k-prod-cpu-2", correlator.correlate(&anomaly("k-prod-cpu-2", "hub-7d9f8b6c5-qz2wm", Transition::Occurred)).unwrap().host);
+}
+
+lazy_static! {
+    static ref CORRELATOR: Mutex<Correlator> = Mutex::new(Correlator::new(CorrelationPolicy::default()));
+}
+
+/// Correlate reports with a policy from now on, keeping open incidents if it is unchanged
+pub fn install(policy: &CorrelationPolicy) {
+    let mut correlator = CORRELATOR.lock().unwrap();
+    if correlator.policy != *policy {
+        *correlator = Correlator::new(policy.clone());
+    }
+}
+
+/// Alert to send for a report, if it is not a duplicate of a cluster-level incident
+pub fn correlate(report: &Report) -> Option<Alert> {
+    let alert = CORRELATOR.lock().unwrap().correlate(report);
+    if let Some(alert) = alert.as_ref().filter(|alert| !alert.hosts.is_empty()) {
+        warn!("{}", alert.summary);
+    }
+    alert
+}
@@ -0,0 +1,148 @@
+use std::path::PathBuf;
+use serde::Deserialize;
+use tokio::prelude::*;
+use crate::config::{self, ConfigError};
+use crate::http::{self, Url};
+use crate::report::PodIdentity;
+
+/// Where and how to reach the Kubernetes API server
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeConfig {
+    /// e.g. https://k-master.dsa.lan:6443
+    pub api_server: String,
+    /// Bearer token of a service account allowed to evict and delete pods
+    pub token_file: Option<PathBuf>,
+    /// CA bundle with which the API server is verified, or the system roots if absent
+    pub ca_cert: Option<PathBuf>
+}
+
+impl KubeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.endpoint().map(|_| ())
+    }
+
+    /// URL of the API server, which has no path
+    fn endpoint(&self) -> Result<Url, String> {
+        let url = Url::parse(&self.api_server).map_err(|e| format!("api_server {}", e))?;
+        if url.path != "/" {
+            return Err(format!("api_server must be <scheme>://<host>[:<port>], got {:?}", self.api_server));
+        }
+        Ok(url)
+    }
+}
+
+#[test]
+fn test_endpoint_parse() {
+    let endpoint = |api_server: &str| KubeConfig { api_server: String::from(api_server), token_file: None, ca_cert: None }.endpoint();
+    assert_eq!(
+        Url { tls: true, host: String::from("k-master.dsa.lan"), port: 6443, path: String::from("/") },
+        endpoint("https://k-master.dsa.lan:6443/").unwrap());
+    assert_eq!(
+        Url { tls: false, host: String::from("localhost"), port: 80, path: String::from("/") },
+        endpoint("http://localhost").unwrap());
+    assert!(endpoint("k-master.dsa.lan:6443").is_err());
+    assert!(endpoint("https://k-master.dsa.lan:6443/api").is_err());
+    assert!(endpoint("https://k-master.dsa.lan:api").is_err());
+}
+
+/// Client of the few pod operations sprinklers need
+pub struct Client {
+    endpoint: Url,
+    token: Option<String>,
+    ca_cert: Option<native_tls::Certificate>
+}
+
+impl Client {
+    /// Read the credentials, which is done for every fix so that rotated tokens are picked up
+    pub fn load(config: &KubeConfig) -> Result<Client, ConfigError> {
+        let endpoint = config.endpoint().map_err(ConfigError::Invalid)?;
+        let token = match &config.token_file {
+            Some(path) => Some(String::from(std::fs::read_to_string(path)
+                .map_err(|e| ConfigError::File(path.clone(), e.to_string()))?.trim())),
+            None => None
+        };
+        let ca_cert = match &config.ca_cert {
+            Some(path) => Some(config::load_cert(path)?),
+            None => None
+        };
+        Ok(Client { endpoint, token, ca_cert })
+    }
+
+    /// Evict a pod, which respects its disruption budget (policy/v1 Evictions, Kubernetes 1.22 and later)
+    pub fn evict(&self, pod: &PodIdentity, grace: u64) -> impl Future<Item = (), Error = String> {
+        let path = format!("/api/v1/namespaces/{}/pods/{}/eviction", pod.namespace, pod.name);
+        let body = serde_json::json!({
+            "apiVersion": "policy/v1",
+            "kind": "Eviction",
+            "metadata": { "name": &pod.name, "namespace": &pod.namespace },
+            "deleteOptions": Client::delete_options(pod, grace)
+        });
+        self.request("POST", &path, body)
+    }
+
+    /// Delete a pod regardless of its disruption budget
+    pub fn delete(&self, pod: &PodIdentity, grace: u64) -> impl Future<Item = (), Error = String> {
+        let path = format!("/api/v1/namespaces/{}/pods/{}", pod.namespace, pod.name);
+        self.request("DELETE", &path, Client::delete_options(pod, grace))
+    }
+
+    /// Options that keep a pod of the same name but a different uid out of harm's way
+    fn delete_options(pod: &PodIdentity, grace: u64) -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "DeleteOptions",
+            "gracePeriodSeconds": grace,
+            "preconditions": { "uid": &pod.uid }
+        })
+    }
+
+    /// Make a request as the service account
+    fn request(&self, method: &str, path: &str, body: serde_json::Value) -> impl Future<Item = (), Error = String> {
+        let headers: Vec<(&str, String)> = self.token.iter()
+            .map(|token| ("Authorization", format!("Bearer {}", token)))
+            .collect();
+        http::request(&self.endpoint, self.ca_cert.as_ref(), method, path, &headers, body.to_string())
+    }
+}
+
+#[cfg(test)]
+fn mock_pod() -> PodIdentity {
+    PodIdentity { namespace: String::from("jhub-prod"), name: String::from("jupyter-alex"), uid: String::from("5f3c"), ..Default::default() }
+}
+
+#[test]
+fn test_kube_evict() {
+    let (url, server) = http::fake_server("HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\r\n{}");
+    let token_file = std::env::temp_dir().join(format!("sprinkler-test-{}.token", std::process::id()));
+    std::fs::write(&token_file, "s3cr3t\n").unwrap();
+    let client = Client::load(&KubeConfig { api_server: url, token_file: Some(token_file.clone()), ca_cert: None }).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(Ok(()), runtime.block_on(client.evict(&mock_pod(), 30)));
+    std::fs::remove_file(&token_file).unwrap();
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /api/v1/namespaces/jhub-prod/pods/jupyter-alex/eviction HTTP/1.0\r\n"));
+    assert!(request.contains("\r\nAuthorization: Bearer s3cr3t\r\n"));
+    let body = http::body_of(&request);
+    assert_eq!("Eviction", body["kind"]);
+    assert_eq!("policy/v1", body["apiVersion"]);
+    assert_eq!("5f3c", body["deleteOptions"]["preconditions"]["uid"]);
+    assert_eq!(30, body["deleteOptions"]["gracePeriodSeconds"]);
+}
+
+#[test]
+fn test_kube_delete_failed() {
+    let (url, server) = http::fake_server(concat!(
+        "HTTP/1.1 409 Conflict\r\nContent-Type: application/json\r\n\r\n",
+        r#"{"kind":"Status","status":"Failure","message":"Precondition failed: UID in precondition: 5f3c, UID in object meta: 7a21","code":409}"#));
+    let client = Client::load(&KubeConfig { api_server: url, token_file: None, ca_cert: None }).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(
+        Err(String::from("DELETE /api/v1/namespaces/jhub-prod/pods/jupyter-alex: Precondition failed: UID in precondition: 5f3c, UID in object meta: 7a21 (409)")),
+        runtime.block_on(client.delete(&mock_pod(), 0)));
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("DELETE /api/v1/namespaces/jhub-prod/pods/jupyter-alex HTTP/1.0\r\n"));
+    assert!(!request.contains("Authorization"));
+}
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use serde::Deserialize;
+use crate::meter::{Clock, SystemClock};
+#[cfg(test)]
+use crate::meter::ManualClock;
+
+/// Limits on how fast a node fixes containers
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitPolicy {
+    /// Fixes per second in the long run
+    pub max_rate: f32,
+    /// Fixes that can be made at once
+    pub burst: u32,
+    /// Fixes within breaker_window seconds that switch the node to notify-only, 0 to never
+    pub breaker_threshold: usize,
+    pub breaker_window: u64,
+    /// Seconds the node stays notify-only
+    pub breaker_cooldown: u64
+}
+
+impl Default for LimitPolicy {
+    fn default() -> Self {
+        LimitPolicy {
+            max_rate: 1.0,
+            burst: 5,
+            breaker_threshold: 20,
+            breaker_window: 60,
+            breaker_cooldown: 600
+        }
+    }
+}
+
+impl LimitPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_rate.is_nan() || self.max_rate <= 0.0 { Err(format!("max_rate must be positive, got {}", self.max_rate)) }
+        else if self.burst == 0 { Err(String::from("burst must be positive")) }
+        else { Ok(()) }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    /// Go ahead
+    Allowed,
+    /// Go ahead, but this is the last fix before the breaker opens
+    Tripped,
+    /// Too many fixes lately
+    RateLimited,
+    /// The breaker is open
+    Open
+}
+
+/// Token bucket of fixes, with a circuit breaker behind it
+pub struct Limiter<C: Clock = SystemClock> {
+    policy: LimitPolicy,
+    tokens: f32,                                 // Fixes that can be made right now
+    t_refill: chrono::DateTime<chrono::Local>,   // Last time the bucket was refilled
+    fixes: VecDeque<chrono::DateTime<chrono::Local>>, // Fixes within the breaker window
+    tripped: Option<std::time::Instant>,         // When the breaker opened, on the monotonic clock
+    clock: C                                     // Source of time
+}
+
+impl Limiter {
+    pub fn new(policy: LimitPolicy) -> Self {
+        Limiter::with_clock(policy, SystemClock)
+    }
+}
+
+impl<C: Clock> Limiter<C> {
+    pub fn with_clock(policy: LimitPolicy, clock: C) -> Self {
+        Limiter {
+            tokens: policy.burst as f32,
+            t_refill: clock.now(),
+            fixes: VecDeque::new(),
+            tripped: None,
+            policy,
+            clock
+        }
+    }
+
+    /// Ask for permission to make a fix
+    pub fn acquire(&mut self) -> Verdict {
+        if self.tripped.is_some() { return Verdict::Open; }
+        let now = self.clock.now();
+        let dt = ((now - self.t_refill).num_milliseconds() as f32) / 1e3;
+        self.tokens = (self.tokens + dt * self.policy.max_rate).min(self.policy.burst as f32);
+        self.t_refill = now;
+        if self.tokens < 1.0 { return Verdict::RateLimited; }
+        self.tokens -= 1.0;
+
+        if self.policy.breaker_threshold == 0 { return Verdict::Allowed; }
+        let window = chrono::Duration::seconds(self.policy.breaker_window as i64);
+        while self.fixes.front().map_or(false, |t| now - *t > window) {
+            self.fixes.pop_front();
+        }
+        self.fixes.push_back(now);
+        if self.fixes.len() >= self.policy.breaker_threshold {
+            self.tripped = Some(self.clock.instant());
+            Verdict::Tripped
+        }
+        else { Verdict::Allowed }
+    }
+
+    /// How long the breaker stays open after tripping
+    pub fn cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.policy.breaker_cooldown)
+    }
+
+    /// Time left until the breaker can be closed, if it is open
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        let elapsed = self.clock.instant().duration_since(self.tripped?);
+        Some(self.cooldown().checked_sub(elapsed).unwrap_or_default())
+    }
+
+    /// Close the breaker if it has cooled down, returning whether it did
+    pub fn reset_if_due(&mut self) -> bool {
+        match self.remaining() {
+            Some(remaining) if remaining == std::time::Duration::from_secs(0) => {
+                self.tripped = None;
+                self.fixes.clear();
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+#[test]
+fn test_limiter_token_bucket() {
+    let clock = ManualClock::default();
+    let policy = LimitPolicy { max_rate: 2.0, burst: 3, breaker_threshold: 0, ..Default::default() };
+    let mut limiter = Limiter::with_clock(policy, clock.clone());
+    assert_eq!(
+        vec![Verdict::Allowed, Verdict::Allowed, Verdict::Allowed, Verdict::RateLimited],
+        (0..4).map(|_| limiter.acquire()).collect::<Vec<Verdict>>());
+
+    clock.advance(chrono::Duration::milliseconds(500)); // One token back
+    assert_eq!(Verdict::Allowed, limiter.acquire());
+    assert_eq!(Verdict::RateLimited, limiter.acquire());
+
+    clock.advance(chrono::Duration::seconds(60)); // No more than the burst
+    assert_eq!(
+        vec![Verdict::Allowed, Verdict::Allowed, Verdict::Allowed, Verdict::RateLimited],
+        (0..4).map(|_| limiter.acquire()).collect::<Vec<Verdict>>());
+}
+
+#[test]
+fn test_limiter_breaker() {
+    let clock = ManualClock::default();
+    let policy = LimitPolicy { max_rate: 1.0, burst: 1, breaker_threshold: 3, breaker_window: 10, breaker_cooldown: 60 };
+    let mut limiter = Limiter::with_clock(policy, clock.clone());
+    assert_eq!(Verdict::Allowed, limiter.acquire());
+    clock.advance(chrono::Duration::seconds(11)); // The first fix falls out of the window
+    assert_eq!(Verdict::Allowed, limiter.acquire());
+    clock.advance(chrono::Duration::seconds(5));
+    assert_eq!(Verdict::Allowed, limiter.acquire());
+    clock.advance(chrono::Duration::seconds(5));
+    assert_eq!(Verdict::Tripped, limiter.acquire());
+
+    clock.advance(chrono::Duration::seconds(30));
+    assert_eq!(Verdict::Open, limiter.acquire());
+    assert!(!limiter.reset_if_due());
+    clock.advance(chrono::Duration::seconds(30));
+    assert!(limiter.reset_if_due());
+    assert!(!limiter.reset_if_due());
+    assert_eq!(Verdict::Allowed, limiter.acquire());
+}
+
+#[test]
+fn test_limiter_breaker_early_timer() {
+    let clock = ManualClock::default();
+    let policy = LimitPolicy { max_rate: 1.0, burst: 1, breaker_threshold: 1, breaker_window: 10, breaker_cooldown: 60 };
+    let mut limiter = Limiter::with_clock(policy, clock.clone());
+    assert_eq!(None, limiter.remaining());
+    assert_eq!(Verdict::Tripped, limiter.acquire());
+
+    // The timer fires before the clock has moved, so the breaker stays open and has to be checked again later
+    assert!(!limiter.reset_if_due());
+    assert_eq!(Some(std::time::Duration::from_secs(60)), limiter.remaining());
+
+    // Stepping the wall clock back does not hold it open
+    clock.step(chrono::Duration::hours(-1));
+    clock.advance(chrono::Duration::seconds(45));
+    assert_eq!(Some(std::time::Duration::from_secs(15)), limiter.remaining());
+    clock.advance(chrono::Duration::seconds(15));
+    assert!(limiter.reset_if_due());
+    assert_eq!(None, limiter.remaining());
+}
@@ -0,0 +1,477 @@
+use serde::Deserialize;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Source of time for meters
+pub trait Clock {
+    fn now(&self) -> chrono::DateTime<chrono::Local>;
+    /// Monotonic time, for durations that must hold when the wall clock is stepped
+    fn instant(&self) -> std::time::Instant;
+}
+
+/// Wall clock
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> chrono::DateTime<chrono::Local> {
+        chrono::Local::now()
+    }
+
+    fn instant(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+}
+
+/// Clock that only moves when told to, shared among its clones
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock(Arc<Mutex<(chrono::DateTime<chrono::Local>, std::time::Instant)>>);
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock(Arc::new(Mutex::new((chrono::Local::now(), std::time::Instant::now()))))
+    }
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.0.lock().unwrap();
+        now.0 = now.0 + duration;
+        now.1 += duration.to_std().unwrap();
+    }
+
+    /// Step the wall clock alone, as NTP or resuming a VM does
+    pub fn step(&self, duration: chrono::Duration) {
+        let mut now = self.0.lock().unwrap();
+        now.0 = now.0 + duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> chrono::DateTime<chrono::Local> {
+        self.0.lock().unwrap().0
+    }
+
+    fn instant(&self) -> std::time::Instant {
+        self.0.lock().unwrap().1
+    }
+}
+
+fn seconds(duration: chrono::Duration) -> f32 {
+    (duration.num_microseconds().unwrap_or(std::i64::MAX) as f32) / 1e6
+}
+
+/// Estimator of the frequency of events
+pub trait RateMeter: Send {
+    /// Trigger the meter counter
+    fn tick(&mut self);
+
+    /// Compute event frequency (Hz)
+    fn read(&self) -> f32;
+
+    /// Time since the meter was last triggered
+    fn idle(&self) -> chrono::Duration;
+}
+
+/// Choice of rate meter, e.g. `{ type = "ewma", time_constant = 2.0 }`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MeterKind {
+    /// Events counted over fixed periods of a second
+    Fixed,
+    /// Events counted over the trailing `window` seconds, kept in `buckets` slots
+    SlidingWindow {
+        #[serde(default = "MeterKind::default_window")]
+        window: f32,
+        #[serde(default = "MeterKind::default_buckets")]
+        buckets: usize
+    },
+    /// Exponentially weighted moving average, forgetting the past over `time_constant` seconds
+    Ewma {
+        #[serde(default = "MeterKind::default_time_constant")]
+        time_constant: f32
+    }
+}
+
+impl Default for MeterKind {
+    fn default() -> Self { MeterKind::Fixed }
+}
+
+impl MeterKind {
+    fn default_window() -> f32 { 1.0 }
+    fn default_buckets() -> usize { 10 }
+    fn default_time_constant() -> f32 { 1.0 }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            MeterKind::Fixed => Ok(()),
+            MeterKind::SlidingWindow { window, buckets } => {
+                if window.is_nan() || *window <= 0.0 { Err(format!("window must be positive, got {}", window)) }
+                else if *buckets == 0 { Err(String::from("buckets must be positive")) }
+                else if (*window as f64) * 1e6 < (*buckets as f64) { Err(String::from("buckets are narrower than a microsecond")) }
+                else { Ok(()) }
+            }
+            MeterKind::Ewma { time_constant } => {
+                if time_constant.is_nan() || *time_constant <= 0.0 { Err(format!("time_constant must be positive, got {}", time_constant)) }
+                else { Ok(()) }
+            }
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn RateMeter> {
+        self.build_with_clock(SystemClock)
+    }
+
+    pub fn build_with_clock<C: Clock + Send + 'static>(&self, clock: C) -> Box<dyn RateMeter> {
+        match self {
+            MeterKind::Fixed => Box::new(EventRateMeter::with_clock(clock)),
+            MeterKind::SlidingWindow { window, buckets } =>
+                Box::new(SlidingWindowMeter::with_clock(clock, *window, *buckets)),
+            MeterKind::Ewma { time_constant } => Box::new(EwmaMeter::with_clock(clock, *time_constant))
+        }
+    }
+}
+
+/// Meter that counts events over fixed periods
+pub struct EventRateMeter<C: Clock = SystemClock> {
+    count: usize,                        // Event counter
+    t0: chrono::DateTime<chrono::Local>, // Starting point of this period
+    t_last: chrono::DateTime<chrono::Local>, // Last time the meter was triggered
+    last_rate: f32,                      // Last reading
+    interval: chrono::Duration,          // Measurement interval
+    clock: C                             // Source of time
+}
+
+impl<C: Clock + Default> Default for EventRateMeter<C> {
+    fn default() -> Self {
+        EventRateMeter::with_clock(C::default())
+    }
+}
+
+impl<C: Clock> EventRateMeter<C> {
+    pub fn with_clock(clock: C) -> Self {
+        EventRateMeter {
+            count: 0,
+            t0: clock.now(),
+            t_last: clock.now(),
+            last_rate: 0f32,
+            interval: chrono::Duration::seconds(1),
+            clock
+        }
+    }
+
+    fn dt(&self) -> f32 {
+        ((((self.clock.now() - self.t0).num_milliseconds()) as f32) / 1e3) + 1e-8
+    }
+}
+
+impl<C: Clock + Send> RateMeter for EventRateMeter<C> {
+    fn tick(&mut self) {
+        self.count += 1;
+        self.t_last = self.clock.now();
+        if self.clock.now() - self.t0 > self.interval {
+            self.last_rate = self.read();
+            self.count = 0;
+            self.t0 = self.clock.now();
+        }
+    }
+
+    fn read(&self) -> f32 {
+        if self.clock.now() - self.t0 < self.interval * 2 {
+            if self.count < 6 && self.last_rate > 0.0 { self.last_rate }
+            else { (self.count as f32) / self.dt() }
+        }
+        else { 0.0 }
+    }
+
+    fn idle(&self) -> chrono::Duration {
+        self.clock.now() - self.t_last
+    }
+}
+
+#[test]
+fn test_event_rate_gt70_hz_1() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(13); // 76.9Hz
+    // Frequency generator
+    for _ in 0..25 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+#[should_panic]
+fn test_event_rate_gt70_hz_2() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(15); // 66.7Hz
+    // Frequency generator
+    for _ in 0..25 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+fn test_event_rate_gt70_hz_1_low_count() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(13); // 76.9Hz
+    // Frequency generator
+    for _ in 0..4 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+#[should_panic]
+fn test_event_rate_gt70_hz_2_low_count() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(15); // 66.7Hz
+    // Frequency generator
+    for _ in 0..4 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+fn test_event_rate_gt70_hz_1_high_count() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(13); // 76.9Hz
+    // Frequency generator
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+#[should_panic]
+fn test_event_rate_gt70_hz_2_high_count() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    let duration = chrono::Duration::milliseconds(15); // 66.7Hz
+    // Frequency generator
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(duration);
+    }
+
+    assert!(meter.read() > 70.0);
+}
+
+#[test]
+fn test_event_rate_expires() {
+    let clock = ManualClock::default();
+    let mut meter = EventRateMeter::with_clock(clock.clone());
+
+    for _ in 0..10 {
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(10));
+    }
+    assert!(meter.read() > 70.0);
+    assert_eq!(chrono::Duration::milliseconds(10), meter.idle());
+
+    clock.advance(chrono::Duration::seconds(2)); // Nothing happens for two intervals
+    assert_eq!(0.0, meter.read());
+}
+
+/// Meter that counts events over a trailing window, in a ring buffer of slots
+pub struct SlidingWindowMeter<C: Clock = SystemClock> {
+    slots: Vec<usize>,                       // Event counters, the oldest following the newest
+    head: usize,                             // Slot of the current time
+    t_head: chrono::DateTime<chrono::Local>, // Starting point of the head slot
+    t0: chrono::DateTime<chrono::Local>,     // Creation of the meter
+    t_last: chrono::DateTime<chrono::Local>, // Last time the meter was triggered
+    width: chrono::Duration,                 // Time covered by a slot
+    clock: C                                 // Source of time
+}
+
+impl<C: Clock> SlidingWindowMeter<C> {
+    pub fn with_clock(clock: C, window: f32, buckets: usize) -> Self {
+        let width = chrono::Duration::microseconds(std::cmp::max(1, ((window as f64) * 1e6 / (buckets as f64)) as i64));
+        SlidingWindowMeter {
+            slots: vec![0; buckets],
+            head: 0,
+            t_head: clock.now(),
+            t0: clock.now(),
+            t_last: clock.now(),
+            width,
+            clock
+        }
+    }
+
+    /// Number of slots the head is behind the current time
+    fn lag(&self) -> usize {
+        let lag = (self.clock.now() - self.t_head).num_microseconds().unwrap_or(std::i64::MAX)
+            / self.width.num_microseconds().unwrap();
+        std::cmp::min(lag as usize, self.slots.len())
+    }
+}
+
+impl<C: Clock + Send> RateMeter for SlidingWindowMeter<C> {
+    fn tick(&mut self) {
+        let lag = self.lag();
+        for _ in 0..lag { // Clear the slots the window has slid past
+            self.head = (self.head + 1) % self.slots.len();
+            self.slots[self.head] = 0;
+        }
+        let now = self.clock.now();
+        if lag == self.slots.len() { self.t_head = now; }
+        else { self.t_head = self.t_head + self.width * lag as i32; }
+        self.slots[self.head] += 1;
+        self.t_last = now;
+    }
+
+    fn read(&self) -> f32 {
+        let lag = self.lag();
+        let len = self.slots.len();
+        // Slots that are still in the window once the head catches up
+        let count: usize = (0..len - lag).map(|i| self.slots[(self.head + len - i) % len]).sum();
+        // From the oldest of these slots until now, unless the meter is younger than that
+        let now = self.clock.now();
+        let span = if lag == len { self.width * len as i32 }
+            else { now - self.t_head + self.width * (len - lag - 1) as i32 };
+        let span = std::cmp::max(std::cmp::min(span, now - self.t0), self.width);
+        (count as f32) / seconds(span)
+    }
+
+    fn idle(&self) -> chrono::Duration {
+        self.clock.now() - self.t_last
+    }
+}
+
+#[test]
+fn test_sliding_window_rate() {
+    let clock = ManualClock::default();
+    let mut meter = SlidingWindowMeter::with_clock(clock.clone(), 1.0, 10);
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(13)); // 76.9Hz
+    }
+    assert!(meter.read() > 70.0);
+
+    let mut meter = SlidingWindowMeter::with_clock(clock.clone(), 1.0, 10);
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(15)); // 66.7Hz
+    }
+    assert!(meter.read() < 70.0);
+}
+
+#[test]
+fn test_sliding_window_burst() {
+    let clock = ManualClock::default();
+    let mut meter = SlidingWindowMeter::with_clock(clock.clone(), 1.0, 10);
+    clock.advance(chrono::Duration::milliseconds(900));
+    for _ in 0..40 { // A burst across the first second
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(5));
+    }
+    assert_eq!(40.0 / 0.9, meter.read()); // Over the last 9 slots
+
+    clock.advance(chrono::Duration::milliseconds(850)); // The first half of the burst slides out
+    assert_eq!(20.0 / 0.95, meter.read());
+    clock.advance(chrono::Duration::milliseconds(100));
+    assert_eq!(0.0, meter.read());
+    assert_eq!(chrono::Duration::milliseconds(955), meter.idle());
+
+    meter.tick(); // Starts over with slots aligned to now
+    assert_eq!(1.0 / 0.9, meter.read());
+}
+
+/// Meter that averages the event rate with exponentially decaying weights
+pub struct EwmaMeter<C: Clock = SystemClock> {
+    rate: f32,                               // Estimate as of the last trigger
+    time_constant: f32,                      // Seconds for the weight of an event to drop by 1/e
+    t_last: chrono::DateTime<chrono::Local>, // Last time the meter was triggered
+    clock: C                                 // Source of time
+}
+
+impl<C: Clock> EwmaMeter<C> {
+    pub fn with_clock(clock: C, time_constant: f32) -> Self {
+        EwmaMeter { rate: 0.0, time_constant, t_last: clock.now(), clock }
+    }
+
+    /// Weight left of the last estimate
+    fn decay(&self) -> f32 {
+        (-seconds(self.clock.now() - self.t_last) / self.time_constant).exp()
+    }
+}
+
+impl<C: Clock + Send> RateMeter for EwmaMeter<C> {
+    fn tick(&mut self) {
+        self.rate = self.rate * self.decay() + 1.0 / self.time_constant;
+        self.t_last = self.clock.now();
+    }
+
+    fn read(&self) -> f32 {
+        self.rate * self.decay()
+    }
+
+    fn idle(&self) -> chrono::Duration {
+        self.clock.now() - self.t_last
+    }
+}
+
+#[test]
+fn test_ewma_rate() {
+    let clock = ManualClock::default();
+    let mut meter = EwmaMeter::with_clock(clock.clone(), 1.0);
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(13)); // 76.9Hz
+    }
+    assert!(meter.read() > 70.0);
+
+    let mut meter = EwmaMeter::with_clock(clock.clone(), 1.0);
+    for _ in 0..300 {
+        meter.tick();
+        clock.advance(chrono::Duration::milliseconds(15)); // 66.7Hz
+    }
+    assert!(meter.read() < 70.0);
+
+    clock.advance(chrono::Duration::seconds(5)); // Fades away
+    assert!(meter.read() < 1.0);
+}
+
+#[test]
+fn test_meter_kind() {
+    #[derive(Deserialize)]
+    struct Policy { meter: MeterKind }
+    let parse = |text: &str| toml::from_str::<Policy>(text).map(|p| p.meter);
+    assert_eq!(MeterKind::Fixed, parse(r#"meter = { type = "fixed" }"#).unwrap());
+    assert_eq!(
+        MeterKind::SlidingWindow { window: 2.0, buckets: 10 },
+        parse(r#"meter = { type = "sliding_window", window = 2.0 }"#).unwrap());
+    assert_eq!(MeterKind::Ewma { time_constant: 0.5 }, parse(r#"meter = { type = "ewma", time_constant = 0.5 }"#).unwrap());
+    assert!(parse(r#"meter = { type = "ewma", window = 0.5 }"#).is_err());
+    assert!(MeterKind::SlidingWindow { window: 1.0, buckets: 0 }.validate().is_err());
+    assert!(MeterKind::Ewma { time_constant: 0.0 }.validate().is_err());
+}
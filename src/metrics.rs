@@ -0,0 +1,173 @@
+use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Observations summed up in <name>_sum and counted in <name>_count
+    Summary
+}
+
+/// Metrics the sprinklers export, with their help
+const FAMILIES: &[(&str, Kind, &str)] = &[
+    ("sprinkler_meter_rate_hz", Kind::Gauge, "Current event rate of a DockerOOM meter, by pod or . (unidentified) and ! (other events)"),
+    ("sprinkler_oom_rate_threshold_hz", Kind::Gauge, "OOM event rate above which a pod is considered anomalous"),
+    ("sprinkler_meters", Kind::Gauge, "Number of DockerOOM meters in use"),
+    ("sprinkler_anomalies", Kind::Gauge, "Number of DockerOOM meters by anomaly state"),
+    ("sprinkler_anomaly_transitions_total", Kind::Counter, "Reported anomaly transitions"),
+    ("sprinkler_remediations_total", Kind::Counter, "Remediation steps by action and outcome"),
+    ("sprinkler_docker_event_lag_seconds", Kind::Gauge, "Age of the last docker event when it was handled"),
+    ("sprinkler_notification_send_seconds", Kind::Summary, "Time taken by attempts to send the master a notification"),
+    ("sprinkler_notification_retries_total", Kind::Counter, "Attempts to send the master a notification after a failed one"),
+    ("sprinkler_notifications_dropped_total", Kind::Counter, "Notifications given up on"),
+    ("sprinkler_reports_received_total", Kind::Counter, "Reports the master has received, by host and kind"),
+    ("sprinkler_report_decode_errors_total", Kind::Counter, "Messages the master has been unable to decode"),
+    ("sprinkler_alerts_total", Kind::Counter, "Alerts sent by sink and result")
+];
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Debug)]
+struct Family {
+    kind: Kind,
+    help: &'static str,
+    /// Values by suffix (_sum and _count of summaries) and labels
+    samples: BTreeMap<(&'static str, Labels), f64>
+}
+
+/// Values of the metrics of this process
+#[derive(Clone, Debug)]
+pub struct Registry {
+    families: BTreeMap<&'static str, Family>
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let families = FAMILIES.iter()
+            .map(|(name, kind, help)| (*name, Family { kind: *kind, help, samples: BTreeMap::new() }))
+            .collect();
+        Registry { families }
+    }
+}
+
+impl Registry {
+    fn sample(&mut self, name: &str, suffix: &'static str, labels: &[(&str, &str)]) -> &mut f64 {
+        let family = self.families.get_mut(name).unwrap_or_else(|| panic!("metric {} is not described", name));
+        let labels = labels.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+        family.samples.entry((suffix, labels)).or_insert(0.0)
+    }
+
+    pub fn add(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        *self.sample(name, "", labels) += value;
+    }
+
+    pub fn set(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        *self.sample(name, "", labels) = value;
+    }
+
+    pub fn observe(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        *self.sample(name, "_sum", labels) += value;
+        *self.sample(name, "_count", labels) += 1.0;
+    }
+
+    /// Forget the samples of a metric with these labels among theirs
+    pub fn remove(&mut self, name: &str, labels: &[(&str, &str)]) {
+        if let Some(family) = self.families.get_mut(name) {
+            family.samples.retain(|(_, sample), _| !labels.iter().all(|(k, v)| sample.iter().any(|(sk, sv)| sk == k && sv == v)));
+        }
+    }
+
+    /// Text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, family) in self.families.iter().filter(|(_, family)| !family.samples.is_empty()) {
+            let kind = match family.kind { Kind::Counter => "counter", Kind::Gauge => "gauge", Kind::Summary => "summary" };
+            let _ = writeln!(text, "# HELP {} {}", name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for ((suffix, labels), value) in family.samples.iter() {
+                let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+                if labels.is_empty() { let _ = writeln!(text, "{}{} {}", name, suffix, value); }
+                else { let _ = writeln!(text, "{}{}{{{}}} {}", name, suffix, labels.join(","), value); }
+            }
+        }
+        text
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+type Collector = Box<dyn Fn(&mut Registry) + Send>;
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+    /// Gauges read at every scrape, by owner
+    static ref COLLECTORS: Mutex<HashMap<String, Collector>> = Mutex::new(HashMap::new());
+}
+
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    REGISTRY.lock().unwrap().add(name, labels, 1.0);
+}
+
+pub fn set(name: &str, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.lock().unwrap().set(name, labels, value);
+}
+
+pub fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.lock().unwrap().observe(name, labels, value);
+}
+
+/// Forget what a sprinkler has set, once it is gone
+pub fn remove(name: &str, labels: &[(&str, &str)]) {
+    REGISTRY.lock().unwrap().remove(name, labels);
+}
+
+/// Have `collector` fill in gauges at every scrape, replacing the one of the same owner
+pub fn collect<F>(owner: String, collector: F) where F: Fn(&mut Registry) + Send + 'static {
+    COLLECTORS.lock().unwrap().insert(owner, Box::new(collector));
+}
+
+pub fn stop_collecting(owner: &str) {
+    COLLECTORS.lock().unwrap().remove(owner);
+}
+
+/// Current values of all metrics in the text exposition format
+pub fn render() -> String {
+    let mut registry = REGISTRY.lock().unwrap().clone();
+    for collector in COLLECTORS.lock().unwrap().values() {
+        collector(&mut registry);
+    }
+    registry.render()
+}
+
+#[test]
+fn test_registry_render() {
+    let mut registry = Registry::default();
+    assert_eq!("", registry.render());
+    registry.add("sprinkler_remediations_total", &[("sprinkler", "1"), ("action", "kill"), ("outcome", "succeeded")], 1.0);
+    registry.add("sprinkler_remediations_total", &[("sprinkler", "1"), ("action", "kill"), ("outcome", "succeeded")], 1.0);
+    registry.set("sprinkler_meter_rate_hz", &[("sprinkler", "1"), ("meter", "jupyter-\"alex\"")], 2.5);
+    registry.observe("sprinkler_notification_send_seconds", &[], 0.25);
+    registry.observe("sprinkler_notification_send_seconds", &[], 0.5);
+    assert_eq!(concat!(
+        "# HELP sprinkler_meter_rate_hz Current event rate of a DockerOOM meter, by pod or . (unidentified) and ! (other events)\n",
+        "# TYPE sprinkler_meter_rate_hz gauge\n",
+        "sprinkler_meter_rate_hz{sprinkler=\"1\",meter=\"jupyter-\\\"alex\\\"\"} 2.5\n",
+        "# HELP sprinkler_notification_send_seconds Time taken by attempts to send the master a notification\n",
+        "# TYPE sprinkler_notification_send_seconds summary\n",
+        "sprinkler_notification_send_seconds_count 2\n",
+        "sprinkler_notification_send_seconds_sum 0.75\n",
+        "# HELP sprinkler_remediations_total Remediation steps by action and outcome\n",
+        "# TYPE sprinkler_remediations_total counter\n",
+        "sprinkler_remediations_total{sprinkler=\"1\",action=\"kill\",outcome=\"succeeded\"} 2\n"
+    ), registry.render());
+
+    registry.remove("sprinkler_meter_rate_hz", &[("sprinkler", "1")]);
+    assert!(!registry.render().contains("sprinkler_meter_rate_hz"));
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, RwLock};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use tokio::prelude::*;
+use crate::report::Report;
+use crate::http;
+use crate::metrics;
+
+/// How a notification is retried when the master cannot be reached
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Delay before the first retry, doubled afterwards
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between retries
+    pub max_delay: Duration,
+    /// Attempts made before the notification is dropped
+    pub max_attempts: u32,
+    /// Time allowed for connecting and sending in one attempt
+    pub timeout: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 10,
+            timeout: Duration::from_secs(10)
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay after a failed attempt (1-based), before jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        std::cmp::min(self.initial_delay.checked_mul(factor).unwrap_or(self.max_delay), self.max_delay)
+    }
+
+    /// Randomize a delay within ±50% so that agents do not retry in lockstep
+    fn jitter(delay: Duration) -> Duration {
+        delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.5))
+    }
+}
+
+#[test]
+fn test_retry_backoff() {
+    let policy = RetryPolicy::default();
+    assert_eq!(
+        vec![1, 2, 4, 8, 16, 32, 60, 60],
+        (1..9).map(|attempt| policy.backoff(attempt).as_secs()).collect::<Vec<u64>>());
+    assert_eq!(policy.max_delay, policy.backoff(100));
+}
+
+#[test]
+fn test_retry_jitter() {
+    for _ in 0..100 {
+        let delay = RetryPolicy::jitter(Duration::from_secs(10));
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+    }
+}
+
+/// Durable storage that takes over the delivery of notifications
+pub trait Spool: Send + Sync {
+    fn push(&self, notification: Notification);
+}
+
+lazy_static! {
+    /// Spool of this process, without which notifications are only kept in memory
+    pub static ref SPOOL: RwLock<Option<Arc<dyn Spool>>> = RwLock::new(None);
+    /// Certificate that the master has to present
+    pub static ref MASTER_CERT: RwLock<Option<native_tls::Certificate>> = RwLock::new(None);
+}
+
+/// An asynchronous message
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Notification {
+    pub report: Report,
+
+    pub from: usize,
+    pub to_addr: String,
+}
+
+impl Notification {
+    pub fn send(self) {
+        match &*SPOOL.read().unwrap() {
+            Some(spool) => spool.push(self),
+            None => { tokio::spawn(self.deliver(RetryPolicy::default())); }
+        }
+    }
+
+    /// Send the notification to the master, retrying with exponential backoff
+    pub fn deliver(self, policy: RetryPolicy) -> impl Future<Item = (), Error = ()> {
+        self.retry(policy).map_err(|(attempts, e)| {
+            error!("Dropped a message to the master after {} attempts: {}", attempts, e);
+            metrics::inc("sprinkler_notifications_dropped_total", &[]);
+        })
+    }
+
+    /// Same as `deliver`, but failing with the number of attempts and the last error once they run out
+    pub fn retry(self, policy: RetryPolicy) -> impl Future<Item = (), Error = (u32, io::Error)> {
+        future::loop_fn((self, 1), move |(notification, attempt)| {
+            let policy = policy.clone();
+            let started = Instant::now();
+            notification.try_send(policy.timeout).then(move |result| {
+                let elapsed = started.elapsed();
+                let outcome = if result.is_ok() { "delivered" } else { "failed" };
+                metrics::observe(
+                    "sprinkler_notification_send_seconds", &[("result", outcome)],
+                    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9);
+                result
+            }).then(move |result| match result {
+                Ok(()) => future::Either::A(future::ok(future::Loop::Break(()))),
+                Err(e) => {
+                    if attempt >= policy.max_attempts {
+                        return future::Either::A(future::err((attempt, e)));
+                    }
+                    metrics::inc("sprinkler_notification_retries_total", &[]);
+                    let delay = RetryPolicy::jitter(policy.backoff(attempt));
+                    debug!("Failed to send the master a message: {}, will retry after {:?}.", e, delay);
+                    future::Either::B(tokio::timer::Delay::new(Instant::now() + delay)
+                        .then(move |_| Ok(future::Loop::Continue((notification, attempt + 1)))))
+                }
+            })
+        })
+    }
+
+    /// Make a single attempt at sending the notification
+    ///
+    /// The master reads one message per connection, so it closing the connection after our shutdown
+    /// is taken as the acknowledgement.
+    fn try_send(&self, timeout: Duration) -> impl Future<Item = (), Error = io::Error> {
+        debug!("Trying to connect to {}", &self.to_addr);
+        let domain = String::from(self.to_addr.split(':').next().unwrap_or_default());
+        let buf = sprinkler_api::compose_message(self.from, self.report.encode());
+        let to_addr = self.to_addr.clone();
+        future::result(tls_connector())
+            .and_then(move |connector| {
+                http::resolve(to_addr)
+                    .and_then(|addr| tokio::net::TcpStream::connect(&addr))
+                    .and_then(move |socket| connector.connect(&domain, socket)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+                    .and_then(move |stream| tokio::io::write_all(stream, buf))
+                    .and_then(|(stream, _)| tokio::io::shutdown(stream))
+                    .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()))
+                    .map(|_| ())
+                    .timeout(timeout)
+                    .map_err(|e| e.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+            })
+    }
+}
+
+fn tls_connector() -> io::Result<tokio_tls::TlsConnector> {
+    let cert = MASTER_CERT.read().unwrap().clone()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the master certificate is not loaded"))?;
+    let mut tlsbuilder = native_tls::TlsConnector::builder();
+    tlsbuilder.add_root_certificate(cert);
+    Ok(tokio_tls::TlsConnector::from(tlsbuilder.build().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?))
+}
+
+#[test]
+fn test_notification_gives_up() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let cert = native_tls::Certificate::from_pem(include_bytes!("../fixtures/master.crt")).unwrap();
+    *MASTER_CERT.write().unwrap() = Some(cert);
+    // A master that hangs up on every connection before the handshake
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let to_addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn({ let connections = connections.clone(); move || {
+        for stream in listener.incoming() {
+            connections.fetch_add(1, Ordering::SeqCst);
+            drop(stream);
+        }
+    }});
+
+    let notification = Notification {
+        report: Report::new(crate::config::SprinklerType::DockerOOM, "localhost", crate::report::Event::Monitor {
+            status: crate::report::MonitorStatus::Deactivated, reason: None
+        }),
+        from: 0,
+        to_addr
+    };
+    let policy = RetryPolicy {
+        initial_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(2),
+        max_attempts: 3,
+        timeout: Duration::from_secs(1)
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(Err(()), runtime.block_on(notification.deliver(policy)));
+    assert_eq!(3, connections.load(Ordering::SeqCst));
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::meter::{Clock, SystemClock};
+#[cfg(test)]
+use crate::meter::ManualClock;
+
+/// How workloads that keep getting fixed are dealt with
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OffenderPolicy {
+    /// Seconds for the record of an offender to fade by half
+    pub half_life: u64,
+    /// Attributes (labels or annotation.*) that identify the user behind a pod, the first present of which is used
+    pub keys: Vec<String>,
+    /// Responses to offenders whose score reaches a level, the highest of which applies
+    pub escalation: Vec<Escalation>
+}
+
+impl Default for OffenderPolicy {
+    fn default() -> Self {
+        OffenderPolicy {
+            half_life: 86400,
+            keys: vec![String::from("annotation.hub.jupyter.org/username")],
+            escalation: Vec::new()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Escalation {
+    /// Fixes (faded by age) from which this applies
+    pub score: f32,
+    /// Grace period to stop the container with instead of remediation.stop_grace
+    pub stop_grace: Option<u64>,
+    /// Stop containers of the offender on this node as soon as they start, until its score fades below this level
+    #[serde(default)]
+    pub block: bool
+}
+
+impl OffenderPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.half_life == 0 { return Err(String::from("half_life must be positive")); }
+        match self.escalation.iter().find(|level| level.score.is_nan() || level.score <= 0.0) {
+            Some(level) => Err(format!("escalation scores must be positive, got {}", level.score)),
+            None => Ok(())
+        }
+    }
+
+    /// Who is behind a container: its namespace and user, or else the workload that owns its pod
+    pub fn offender_of(&self, attributes: &HashMap<String, String>) -> String {
+        let label = |key: &str| attributes.get(key).map(String::as_str).unwrap_or_default();
+        match attributes.get("io.kubernetes.pod.name") {
+            Some(pod) => {
+                let owner = self.keys.iter().filter_map(|key| attributes.get(key)).next()
+                    .map(String::as_str)
+                    .unwrap_or_else(|| workload_of(pod));
+                format!("{}/{}", label("io.kubernetes.pod.namespace"), owner)
+            }
+            None => String::from(label("name"))
+        }
+    }
+}
+
+/// Characters of the random suffixes Kubernetes puts in generated names
+const SUFFIX_ALPHABET: &str = "bcdfghjklmnpqrstvwxz2456789";
+
+/// Guess the workload of a pod by stripping what controllers append to its name,
+/// e.g. hub-7d9f8b6c5-x2x4z, backup-1571234567-c2xwk and web-0
+pub fn workload_of(pod: &str) -> &str {
+    let generated = |segment: &str, lengths: &[usize]| lengths.contains(&segment.len())
+        && segment.chars().all(|c| SUFFIX_ALPHABET.contains(c));
+    let mut name = pod;
+    let mut strip = |matches: &dyn Fn(&str) -> bool| {
+        if let Some(i) = name.rfind('-') {
+            if i > 0 && matches(&name[i + 1..]) { name = &name[..i]; }
+        }
+    };
+    strip(&|segment| generated(segment, &[5]));              // Pod of a ReplicaSet, Job or DaemonSet
+    strip(&|segment| generated(segment, &[8, 9, 10]));       // ReplicaSet of a Deployment
+    strip(&|segment| !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit())); // StatefulSet or CronJob
+    name
+}
+
+#[test]
+fn test_workload_of() {
+    assert_eq!("hub", workload_of("hub-7d9f8b6c5-x2x4z"));
+    assert_eq!("proxy", workload_of("proxy-5c7d4b8f6b-qz2wm"));
+    assert_eq!("backup", workload_of("backup-1571234567-c2xwk"));
+    assert_eq!("web", workload_of("web-0"));
+    assert_eq!("fluentd", workload_of("fluentd-x7kq4"));
+    assert_eq!("jupyter-alex", workload_of("jupyter-alex"));
+    assert_eq!("etcd-k-master", workload_of("etcd-k-master"));
+}
+
+#[test]
+fn test_offender_of() {
+    let policy = OffenderPolicy::default();
+    let mut attributes = HashMap::new();
+    attributes.insert(String::from("io.kubernetes.pod.namespace"), String::from("jhub-prod"));
+    attributes.insert(String::from("io.kubernetes.pod.name"), String::from("hub-7d9f8b6c5-x2x4z"));
+    assert_eq!("jhub-prod/hub", policy.offender_of(&attributes));
+    attributes.insert(String::from("io.kubernetes.pod.name"), String::from("jupyter-alex-2d"));
+    attributes.insert(String::from("annotation.hub.jupyter.org/username"), String::from("alex"));
+    assert_eq!("jhub-prod/alex", policy.offender_of(&attributes));
+
+    let mut attributes = HashMap::new();
+    attributes.insert(String::from("name"), String::from("registry"));
+    assert_eq!("registry", policy.offender_of(&attributes));
+}
+
+/// Faded count of fixes of an offender
+struct Record {
+    score: f32,
+    t_last: chrono::DateTime<chrono::Local>, // Last time the score was updated
+    level: Option<usize>                     // Escalation level reached
+}
+
+/// Standing of an offender after another fix
+#[derive(Clone, Debug, PartialEq)]
+pub struct Standing {
+    pub score: f32,
+    /// What applies to the offender
+    pub escalation: Option<Escalation>,
+    /// Whether the offender has just reached a higher level
+    pub escalated: bool
+}
+
+/// History of fixes by offender
+pub struct Offenders<C: Clock = SystemClock> {
+    policy: OffenderPolicy,
+    records: HashMap<String, Record>,
+    clock: C
+}
+
+/// Score below which an offender is forgotten
+const FORGOTTEN: f32 = 0.05;
+
+impl Offenders {
+    pub fn new(policy: OffenderPolicy) -> Self {
+        Offenders::with_clock(policy, SystemClock)
+    }
+}
+
+impl<C: Clock> Offenders<C> {
+    pub fn with_clock(policy: OffenderPolicy, clock: C) -> Self {
+        Offenders { policy, records: HashMap::new(), clock }
+    }
+
+    fn faded(&self, record: &Record) -> f32 {
+        let age = ((self.clock.now() - record.t_last).num_milliseconds() as f32) / 1e3;
+        record.score * (0.5f32).powf(age / (self.policy.half_life as f32))
+    }
+
+    /// Count another fix against an offender
+    pub fn offend(&mut self, offender: &str) -> Standing {
+        let now = self.clock.now();
+        // Forget offenders who have behaved for long enough
+        let forgotten: Vec<String> = self.records.iter()
+            .filter(|(_, record)| self.faded(record) < FORGOTTEN)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in forgotten {
+            self.records.remove(&key);
+        }
+
+        let score = self.records.get(offender).map_or(0.0, |record| self.faded(record)) + 1.0;
+        let level = self.policy.escalation.iter().enumerate()
+            .filter(|(_, level)| score >= level.score)
+            .max_by(|(_, a), (_, b)| a.score.partial_cmp(&b.score).unwrap())
+            .map(|(i, _)| i);
+        let escalation = &self.policy.escalation;
+        let previous = self.records.insert(String::from(offender), Record { score, t_last: now, level })
+            .and_then(|record| record.level);
+        let escalated = level.is_some() && level.map(|i| escalation[i].score) > previous.map(|i| escalation[i].score);
+        Standing { score, escalation: level.map(|i| self.policy.escalation[i].clone()), escalated }
+    }
+
+    /// Blocking escalation that still applies to an offender, if any
+    pub fn blocked(&self, offender: &str) -> Option<Escalation> {
+        let score = self.faded(self.records.get(offender)?);
+        self.policy.escalation.iter()
+            .filter(|level| level.block && score >= level.score)
+            .max_by(|a, b| a.score.partial_cmp(&b.score).unwrap())
+            .cloned()
+    }
+}
+
+#[test]
+fn test_offenders() {
+    let clock = ManualClock::default();
+    let policy = OffenderPolicy {
+        half_life: 3600,
+        escalation: vec![
+            Escalation { score: 4.0, stop_grace: Some(0), block: true },
+            Escalation { score: 2.0, stop_grace: Some(2), block: false }
+        ],
+        ..Default::default()
+    };
+    let mut offenders = Offenders::with_clock(policy.clone(), clock.clone());
+    let standing = offenders.offend("jhub-prod/alex");
+    assert_eq!(Standing { score: 1.0, escalation: None, escalated: false }, standing);
+    let standing = offenders.offend("jhub-prod/alex");
+    assert_eq!(Standing { score: 2.0, escalation: Some(policy.escalation[1].clone()), escalated: true }, standing);
+    assert_eq!(1.0, offenders.offend("jhub-prod/bob").score);
+
+    clock.advance(chrono::Duration::hours(1)); // Half forgiven
+    let standing = offenders.offend("jhub-prod/alex");
+    assert_eq!(Standing { score: 2.0, escalation: Some(policy.escalation[1].clone()), escalated: false }, standing);
+    let standing = offenders.offend("jhub-prod/alex");
+    assert_eq!(3.0, standing.score);
+    assert_eq!(None, offenders.blocked("jhub-prod/alex"));
+    let standing = offenders.offend("jhub-prod/alex");
+    assert_eq!(Standing { score: 4.0, escalation: Some(policy.escalation[0].clone()), escalated: true }, standing);
+    assert_eq!(Some(policy.escalation[0].clone()), offenders.blocked("jhub-prod/alex"));
+    assert_eq!(None, offenders.blocked("jhub-prod/bob"));
+
+    clock.advance(chrono::Duration::minutes(10)); // Faded below the blocking level
+    assert_eq!(None, offenders.blocked("jhub-prod/alex"));
+
+    clock.advance(chrono::Duration::hours(24)); // All forgiven
+    assert_eq!(Standing { score: 1.0, escalation: None, escalated: false }, offenders.offend("jhub-prod/alex"));
+    assert_eq!(1, offenders.records.len());
+}
@@ -0,0 +1,317 @@
+use std::io::{self, BufRead, Write};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use serde::{Serialize, Deserialize};
+use tokio::prelude::*;
+use crate::notification::{self, Notification, RetryPolicy};
+use crate::report::Report;
+#[cfg(test)]
+use crate::report::Transition;
+
+/// A line of the journal
+#[derive(Serialize, Deserialize)]
+enum Record {
+    /// A notification has been queued
+    Push(u64, Notification),
+    /// The master has received the notification with this sequence number
+    Ack(u64)
+}
+
+/// Acknowledgements after which the journal is compacted while notifications are still pending
+const COMPACT_AFTER: usize = 1000;
+
+/// Append-only journal of notifications pending delivery
+struct Journal {
+    path: PathBuf,
+    file: File,
+    next_seq: u64,
+    pending: VecDeque<(u64, Notification)>,
+    acks: usize,         // Acknowledgements appended since the last compaction
+    compact_after: usize
+}
+
+impl Journal {
+    /// Open a journal and recover the notifications yet to be acknowledged
+    ///
+    /// Records that cannot be read, other than the last one being cut short by a crash, are set aside
+    /// in a .rejected file next to the journal rather than dropped.
+    fn open(path: &Path) -> io::Result<Journal> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut next_seq = 0;
+        let mut pending = VecDeque::new();
+        let lines = match File::open(path) {
+            Ok(file) => io::BufReader::new(file).lines().collect::<io::Result<Vec<String>>>()?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        let mut rejected = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            match decode_record(line) {
+                Ok(Record::Push(seq, notification)) => {
+                    next_seq = seq + 1;
+                    pending.push_back((seq, notification));
+                }
+                Ok(Record::Ack(seq)) => pending.retain(|(s, _)| *s != seq),
+                Err(e) if i + 1 == lines.len() && serde_json::from_str::<serde_json::Value>(line).is_err() =>
+                    warn!("{}:{}: skipping a record cut short by a crash: {}", path.display(), i + 1, e),
+                Err(e) => {
+                    error!("{}:{}: unable to read a record: {}, setting it aside", path.display(), i + 1, e);
+                    rejected.push(line.as_str());
+                }
+            }
+        }
+        if !rejected.is_empty() {
+            let mut file = OpenOptions::new().create(true).append(true).open(path.with_extension("rejected"))?;
+            file.write_all(format!("{}\n", rejected.join("\n")).as_bytes())?;
+            file.sync_data()?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut journal = Journal { path: PathBuf::from(path), file, next_seq, pending, acks: 0, compact_after: COMPACT_AFTER };
+        journal.compact()?;
+        Ok(journal)
+    }
+
+    /// Rewrite the journal down to the pending notifications
+    fn compact(&mut self) -> io::Result<()> {
+        let compacted = self.path.with_extension("compacting");
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&compacted)?;
+        let mut lines = Vec::new();
+        for (seq, notification) in self.pending.iter() {
+            lines.extend(line(&Record::Push(*seq, notification.clone()))?);
+        }
+        file.write_all(&lines)?;
+        file.sync_data()?;
+        std::fs::rename(&compacted, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.acks = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        self.file.write_all(&line(record)?)?;
+        self.file.sync_data()
+    }
+
+    fn push(&mut self, notification: Notification) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.append(&Record::Push(seq, notification.clone()))?;
+        self.next_seq += 1;
+        self.pending.push_back((seq, notification));
+        Ok(())
+    }
+
+    fn ack(&mut self, seq: u64) -> io::Result<()> {
+        self.pending.retain(|(s, _)| *s != seq);
+        if self.pending.is_empty() { // Nothing worth keeping
+            self.acks = 0;
+            self.file.set_len(0)
+        }
+        else if self.acks + 1 >= self.compact_after {
+            self.compact()
+        }
+        else {
+            self.acks += 1;
+            self.append(&Record::Ack(seq))
+        }
+    }
+
+    fn head(&self) -> Option<(u64, Notification)> {
+        self.pending.front().cloned()
+    }
+}
+
+/// Read a line of the journal, bringing reports of older versions up to date
+fn decode_record(line: &str) -> Result<Record, String> {
+    let mut record: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    if let Some(report) = record.pointer_mut("/Push/1/report") {
+        let decoded = Report::decode(&report.to_string()).map_err(|e| e.to_string())?;
+        *report = serde_json::to_value(decoded).map_err(|e| e.to_string())?;
+    }
+    serde_json::from_value(record).map_err(|e| e.to_string())
+}
+
+fn line(record: &Record) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+lazy_static! {
+    /// Address of the master as currently configured, which journaled notifications are delivered to
+    static ref MASTER_ADDR: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Deliver journaled notifications to the master at `master_addr` from now on, whichever master they
+/// were first meant for
+pub fn retarget(master_addr: &str) {
+    *MASTER_ADDR.write().unwrap() = Some(String::from(master_addr));
+}
+
+/// A notification as it is to be delivered now
+fn addressed(mut notification: Notification) -> Notification {
+    if let Some(master_addr) = &*MASTER_ADDR.read().unwrap() {
+        notification.to_addr = master_addr.clone();
+    }
+    notification
+}
+
+/// Spool that delivers notifications in order and retries until the master acknowledges them
+#[derive(Clone)]
+pub struct Outbox {
+    journal: Arc<Mutex<Journal>>,
+    wakeup: futures::sync::mpsc::UnboundedSender<()>
+}
+
+impl Outbox {
+    /// Open the journal at `path` and start delivering what it holds
+    pub fn install(path: &Path) -> io::Result<()> {
+        let journal = Journal::open(path)?;
+        if !journal.pending.is_empty() {
+            info!("{}: {} notifications pending delivery", path.display(), journal.pending.len());
+        }
+        let (wakeup, wakeup_rx) = futures::sync::mpsc::unbounded();
+        let outbox = Outbox { journal: Arc::new(Mutex::new(journal)), wakeup };
+        outbox.wakeup.unbounded_send(()).unwrap();
+        tokio::spawn(wakeup_rx.for_each({ let outbox = outbox.clone(); move |_| outbox.drain() }));
+        *notification::SPOOL.write().unwrap() = Some(Arc::new(outbox));
+        Ok(())
+    }
+
+    /// Deliver pending notifications one after another
+    ///
+    /// A notification is never given up on once it is in the journal. It is retried in rounds, each of
+    /// which goes to the master as configured at the time, so that a new master_addr takes effect.
+    fn drain(&self) -> impl Future<Item = (), Error = ()> {
+        let journal = self.journal.clone();
+        future::loop_fn((), move |_| {
+            let head = journal.lock().unwrap().head();
+            let journal = journal.clone();
+            match head {
+                None => future::Either::A(future::ok(future::Loop::Break(()))),
+                Some((seq, notification)) => future::Either::B(addressed(notification).retry(RetryPolicy::default()).then(move |result| {
+                    match result {
+                        Ok(()) => if let Err(e) = journal.lock().unwrap().ack(seq) {
+                            // It may be delivered again after a restart
+                            error!("Unable to acknowledge notification #{} in the journal: {}", seq, e);
+                        },
+                        Err((attempts, e)) =>
+                            warn!("Unable to deliver notification #{} after {} attempts: {}, trying again", seq, attempts, e)
+                    }
+                    Ok(future::Loop::Continue(()))
+                }))
+            }
+        })
+    }
+}
+
+impl notification::Spool for Outbox {
+    fn push(&self, notification: Notification) {
+        let mut journal = self.journal.lock().unwrap();
+        if let Err(e) = journal.push(notification.clone()) {
+            error!("{}: {}, sending without the journal", journal.path.display(), e);
+            tokio::spawn(notification.deliver(Default::default()));
+            return;
+        }
+        let _ = self.wakeup.unbounded_send(());
+    }
+}
+
+#[cfg(test)]
+fn mock_notification(transition: Transition) -> Notification {
+    use crate::config::SprinklerType;
+    use crate::report::{Report, Event, Subject};
+    let event = Event::Anomaly { subject: Subject::Docker, transition };
+    Notification { report: Report::new(SprinklerType::DockerOOM, "localhost", event), from: 0, to_addr: String::from("bridge.dsa.lan:3777") }
+}
+
+#[cfg(test)]
+fn pending_messages(journal: &Journal) -> Vec<String> {
+    journal.pending.iter().map(|(_, n)| format!("{}", n.report.event)).collect()
+}
+
+#[test]
+fn test_journal_recovery() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}/outbox.journal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    {
+        let mut journal = Journal::open(&path).unwrap();
+        for transition in &[Transition::Occurred, Transition::Fixing, Transition::Fixed] {
+            journal.push(mock_notification(*transition)).unwrap();
+        }
+        let (seq, _) = journal.head().unwrap();
+        journal.ack(seq).unwrap();
+    }
+    // A record torn by a crash
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"Push\":[3,{\"da").unwrap();
+
+    {
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(vec!["docker Fixing", "docker Fixed"], pending_messages(&journal));
+        journal.push(mock_notification(Transition::GaveUp)).unwrap();
+        assert_eq!(3, journal.pending.back().unwrap().0);
+    }
+
+    let mut journal = Journal::open(&path).unwrap();
+    assert_eq!(vec!["docker Fixing", "docker Fixed", "docker GaveUp"], pending_messages(&journal));
+    while let Some((seq, _)) = journal.head() {
+        journal.ack(seq).unwrap();
+    }
+    assert_eq!(0, std::fs::metadata(&path).unwrap().len());
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_journal_versions() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}-versions/outbox.journal", std::process::id()));
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let push = |seq: u64, version: u32, transition: &str| {
+        let mut record = serde_json::to_value(Record::Push(seq, mock_notification(Transition::Occurred))).unwrap();
+        record["Push"][1]["report"]["version"] = serde_json::json!(version);
+        record["Push"][1]["report"]["event"]["transition"] = serde_json::json!(transition);
+        record.to_string()
+    };
+    let newer = push(1, 99, "occurred");
+    let lines = [push(0, 2, "GaveUp"), newer.clone(), push(2, 3, "fixed"), String::from("{\"Push\":[3,{\"rep")];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    // Older reports are brought up to date, and those that cannot be read are set aside but for a torn tail
+    let journal = Journal::open(&path).unwrap();
+    assert_eq!(vec!["docker GaveUp", "docker Fixed"], pending_messages(&journal));
+    assert_eq!(format!("{}\n", newer), std::fs::read_to_string(path.with_extension("rejected")).unwrap());
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_journal_compaction() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}-compaction/outbox.journal", std::process::id()));
+    let mut journal = Journal::open(&path).unwrap();
+    journal.compact_after = 3;
+    for _ in 0..5 {
+        journal.push(mock_notification(Transition::Occurred)).unwrap();
+    }
+    let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+    journal.ack(0).unwrap();
+    journal.ack(1).unwrap();
+    assert_eq!(7, lines(&path));
+
+    // A long drain does not grow the journal past the pending notifications and a few acknowledgements
+    journal.ack(2).unwrap();
+    assert_eq!(2, lines(&path));
+    journal.ack(3).unwrap();
+    assert_eq!(3, lines(&path));
+    drop(journal);
+    assert_eq!(vec!["docker Occurred"], pending_messages(&Journal::open(&path).unwrap()));
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_outbox_retarget() {
+    let notification = mock_notification(Transition::Occurred);
+    assert_eq!("bridge.dsa.lan:3777", addressed(notification.clone()).to_addr);
+    retarget("bridge-2.dsa.lan:3777");
+    assert_eq!("bridge-2.dsa.lan:3777", addressed(notification).to_addr);
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use sprinkler_api::AnomalyTransition;
+use crate::config::SprinklerType;
+
+/// Version of the report format, bumped on incompatible changes
+pub const REPORT_VERSION: u32 = 3;
+
+/// What an agent tells the master, encoded as JSON in the message body
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub version: u32,
+    /// Type of the reporting sprinkler
+    pub sprinkler: SprinklerType,
+    /// Host of the reporting sprinkler
+    pub host: String,
+    /// When the agent made the report
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub event: Event
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// The anomaly state of a subject has changed
+    Anomaly { subject: Subject, transition: Transition },
+    /// An attempt has been made to fix a container
+    Remediation { subject: Subject, container: String, action: Action, outcome: Outcome },
+    /// The health of the sprinkler itself has changed
+    Monitor { status: MonitorStatus, reason: Option<String> },
+    /// A workload or user has been fixed often enough lately to be dealt with more harshly
+    /// (block: its containers are stopped as they start on that node for a while)
+    Offender { subject: Subject, offender: String, score: f32, block: bool }
+}
+
+/// What an event is about
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Subject {
+    /// A container managed by Kubernetes
+    Pod(PodIdentity),
+    /// A container that is not managed by Kubernetes
+    Container { name: String },
+    /// The docker daemon as a whole
+    Docker
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PodIdentity {
+    pub namespace: String,
+    pub name: String,
+    pub uid: String,
+    /// Image of the container, empty if unknown
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub image: String
+}
+
+impl PodIdentity {
+    /// Identify a pod by the io.kubernetes.pod.* labels of one of its containers, along with its image
+    pub fn from_labels(labels: &HashMap<String, String>) -> Self {
+        let label = |key: &str| labels.get(key).cloned().unwrap_or_default();
+        PodIdentity {
+            namespace: label("io.kubernetes.pod.namespace"),
+            name: label("io.kubernetes.pod.name"),
+            uid: label("io.kubernetes.pod.uid"),
+            image: label("image")
+        }
+    }
+}
+
+/// Change of the anomaly state of a subject, after sprinkler_api::AnomalyTransition
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    Normal,
+    Overshoot,
+    Undershoot,
+    Occurred,
+    Unhandled,
+    Disappeared,
+    Fixing,
+    Fixed,
+    HasFixed,
+    GaveUp
+}
+
+impl From<AnomalyTransition> for Transition {
+    fn from(transition: AnomalyTransition) -> Self {
+        match transition {
+            AnomalyTransition::Normal => Transition::Normal,
+            AnomalyTransition::Overshoot => Transition::Overshoot,
+            AnomalyTransition::Undershoot => Transition::Undershoot,
+            AnomalyTransition::Occurred => Transition::Occurred,
+            AnomalyTransition::Unhandled => Transition::Unhandled,
+            AnomalyTransition::Disappeared => Transition::Disappeared,
+            AnomalyTransition::Fixing => Transition::Fixing,
+            AnomalyTransition::Fixed => Transition::Fixed,
+            AnomalyTransition::HasFixed => Transition::HasFixed,
+            AnomalyTransition::GaveUp => Transition::GaveUp
+        }
+    }
+}
+
+impl Transition {
+    /// Whether the subject still has an anomaly afterwards
+    pub fn is_anomalous(self) -> bool {
+        match self {
+            Transition::Normal | Transition::Disappeared | Transition::Fixed | Transition::HasFixed => false,
+            _ => true
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Asked to stop within a grace period
+    Stop,
+    /// Sent SIGKILL
+    Kill,
+    /// Removed along with its writable layer
+    Remove,
+    /// Had its pod evicted through the Kubernetes API
+    Evict,
+    /// Had its pod deleted through the Kubernetes API
+    Delete,
+    /// Had its cgroup frozen
+    Freeze,
+    /// Had its cgroup thawed after being frozen
+    Thaw,
+    /// Had its memory or CPU limits lowered
+    Throttle,
+    /// Had its memory and CPU limits restored after being throttled
+    Unthrottle
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed(String),
+    /// Nothing has been done in dry-run mode
+    DryRun,
+    /// Nothing has been done because of the limits on fixes
+    Suppressed(String)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitorStatus {
+    /// Events are not being monitored for the time being
+    Degraded,
+    /// Monitoring has resumed after being degraded
+    Recovered,
+    /// The sprinkler has been shut down
+    Deactivated,
+    /// Too many fixes have been made, so anomalies are only reported for a while
+    BreakerTripped,
+    /// Fixes are made again after the breaker has cooled down
+    BreakerReset
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    /// The report is of a version that cannot be read, such as one from an agent speaking a newer version
+    Version(u32)
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "malformed report: {}", e),
+            DecodeError::Version(version) => write!(f, "unsupported report version {} (expecting 1 to {})", version, REPORT_VERSION)
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Report {
+    pub fn new(sprinkler: SprinklerType, host: &str, event: Event) -> Self {
+        Report {
+            version: REPORT_VERSION,
+            sprinkler,
+            host: String::from(host),
+            time: chrono::Utc::now(),
+            event
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("reports are always serializable")
+    }
+
+    /// Decode a report of this version or an older one, which is brought up to date
+    pub fn decode(body: &str) -> Result<Report, DecodeError> {
+        #[derive(Deserialize)]
+        struct Versioned { version: u32 }
+        let Versioned { version } = serde_json::from_str(body).map_err(DecodeError::Json)?;
+        if version == 0 || version > REPORT_VERSION {
+            return Err(DecodeError::Version(version));
+        }
+        if version == REPORT_VERSION {
+            return serde_json::from_str(body).map_err(DecodeError::Json);
+        }
+        let report = serde_json::from_str(body).map_err(DecodeError::Json)?;
+        serde_json::from_value(migrate(report, version)).map_err(DecodeError::Json)
+    }
+}
+
+/// Bring a report of an older version up to the current one
+fn migrate(mut report: serde_json::Value, version: u32) -> serde_json::Value {
+    let event = &mut report["event"];
+    if version < 2 && event["action"] == "kill_and_remove" {
+        // Version 2 split KillAndRemove into a ladder of steps, which began with a kill back then
+        event["action"] = serde_json::json!("kill");
+    }
+    if version < 3 {
+        // Transitions were the Debug text of AnomalyTransition, e.g. GaveUp for gave_up
+        if let Some(transition) = event["transition"].as_str().map(snake_case) {
+            event["transition"] = serde_json::json!(transition);
+        }
+    }
+    report["version"] = serde_json::json!(REPORT_VERSION);
+    report
+}
+
+fn snake_case(text: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in text.chars().enumerate() {
+        if c.is_uppercase() && i > 0 { snake.push('_'); }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subject::Pod(pod) => write!(f, "pod {}/{}", pod.namespace, pod.name),
+            Subject::Container { name } => write!(f, "container {}", name),
+            Subject::Docker => write!(f, "docker")
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Anomaly { subject, transition } => write!(f, "{} {:?}", subject, transition),
+            Event::Remediation { subject, container, action, outcome: Outcome::DryRun } =>
+                write!(f, "would {:?} {} of {}", action, container, subject),
+            Event::Remediation { subject, container, action, outcome } =>
+                write!(f, "{:?} {} of {} => {:?}", action, container, subject, outcome),
+            Event::Monitor { status, reason: Some(reason) } => write!(f, "{:?}: {}", status, reason),
+            Event::Monitor { status, reason: None } => write!(f, "{:?}", status),
+            Event::Offender { subject, offender, score, block } => write!(
+                f, "{} is a repeat offender (score {:.1}) as {}{}",
+                subject, score, offender, if *block { ", blocking its respawns" } else { "" })
+        }
+    }
+}
+
+#[test]
+fn test_report_roundtrip() {
+    let mut labels = HashMap::new();
+    labels.insert(String::from("io.kubernetes.pod.namespace"), String::from("jhub-prod"));
+    labels.insert(String::from("io.kubernetes.pod.name"), String::from("jupyter-alex"));
+    labels.insert(String::from("io.kubernetes.pod.uid"), String::from("5f3c"));
+    labels.insert(String::from("image"), String::from("sha256:53d2e4e10e73"));
+    let report = Report::new(SprinklerType::DockerOOM, "k-prod-cpu-1.dsa.lan", Event::Anomaly {
+        subject: Subject::Pod(PodIdentity::from_labels(&labels)),
+        transition: Transition::GaveUp
+    });
+    assert_eq!(report, Report::decode(&report.encode()).unwrap());
+    assert!(report.encode().contains(r#""sprinkler":"DockerOOM""#));
+    assert!(report.encode().contains(r#""transition":"gave_up""#));
+    assert_eq!("pod jhub-prod/jupyter-alex GaveUp", format!("{}", report.event));
+
+    let report = Report::new(SprinklerType::DockerOOM, "k-prod-cpu-1.dsa.lan", Event::Remediation {
+        subject: Subject::Pod(PodIdentity::from_labels(&labels)),
+        container: String::from("4c01db0b339c"),
+        action: Action::Stop,
+        outcome: Outcome::DryRun
+    });
+    assert_eq!(report, Report::decode(&report.encode()).unwrap());
+    assert_eq!("would Stop 4c01db0b339c of pod jhub-prod/jupyter-alex", format!("{}", report.event));
+}
+
+#[test]
+fn test_report_decode_errors() {
+    match Report::decode("msg = DockerOOM Occurred") {
+        Err(DecodeError::Json(_)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+    let mut report = serde_json::to_value(Report::new(SprinklerType::DockerOOM, "k-prod-cpu-1.dsa.lan", Event::Monitor {
+        status: MonitorStatus::Deactivated, reason: None
+    })).unwrap();
+    report["version"] = serde_json::json!(REPORT_VERSION + 1);
+    match Report::decode(&report.to_string()) {
+        Err(DecodeError::Version(version)) => assert_eq!(REPORT_VERSION + 1, version),
+        other => panic!("unexpected {:?}", other)
+    }
+    report["version"] = serde_json::json!(0);
+    match Report::decode(&report.to_string()) {
+        Err(DecodeError::Version(0)) => (),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn test_report_decode_older() {
+    let pod = Subject::Pod(PodIdentity {
+        namespace: String::from("jhub-prod"), name: String::from("jupyter-alex"), uid: String::from("5f3c"), ..Default::default()
+    });
+    let v1 = Report::decode(concat!(
+        r#"{"version":1,"sprinkler":"DockerOOM","host":"k-prod-cpu-1","time":"2026-10-12T08:00:00Z","#,
+        r#""event":{"kind":"remediation","subject":{"type":"pod","namespace":"jhub-prod","name":"jupyter-alex","uid":"5f3c"},"#,
+        r#""container":"4c01db0b339c","action":"kill_and_remove","outcome":"succeeded"}}"#)).unwrap();
+    assert_eq!(REPORT_VERSION, v1.version);
+    assert_eq!(Event::Remediation {
+        subject: pod.clone(), container: String::from("4c01db0b339c"), action: Action::Kill, outcome: Outcome::Succeeded
+    }, v1.event);
+
+    let v2 = Report::decode(concat!(
+        r#"{"version":2,"sprinkler":"DockerOOM","host":"k-prod-cpu-1","time":"2026-10-12T08:00:00Z","#,
+        r#""event":{"kind":"anomaly","subject":{"type":"pod","namespace":"jhub-prod","name":"jupyter-alex","uid":"5f3c"},"#,
+        r#""transition":"GaveUp"}}"#)).unwrap();
+    assert_eq!((SprinklerType::DockerOOM, Event::Anomaly { subject: pod, transition: Transition::GaveUp }),
+               (v2.sprinkler, v2.event));
+}
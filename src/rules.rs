@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+
+/// What is done about a container that keeps running out of memory
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Neither meter nor report it
+    Ignore,
+    /// Report anomalies without fixing anything
+    Notify,
+    /// Take the container down through docker
+    Kill,
+    /// Evict its pod through the Kubernetes API
+    Evict
+}
+
+/// An entry of the policy table, which applies to containers matching all of its patterns
+///
+/// Settings left out fall back to those of the policy.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Glob of the namespace of the pod
+    pub namespace: Option<String>,
+    /// Glob of the name of the pod
+    pub pod: Option<String>,
+    /// Globs of container labels
+    pub labels: HashMap<String, String>,
+    /// What is done instead of what the remediation settings say
+    pub action: Option<RuleAction>,
+    pub oom_rate: Option<f32>,
+    pub max_retry: Option<usize>,
+    pub divider: Option<usize>
+}
+
+impl Rule {
+    /// Whether the rule applies to a container with these attributes (labels)
+    pub fn matches(&self, attributes: &HashMap<String, String>) -> bool {
+        let matches = |key: &str, pattern: &str| attributes.get(key).map_or(false, |value| glob_match(pattern, value));
+        self.namespace.as_ref().map_or(true, |pattern| matches("io.kubernetes.pod.namespace", pattern))
+            && self.pod.as_ref().map_or(true, |pattern| matches("io.kubernetes.pod.name", pattern))
+            && self.labels.iter().all(|(key, pattern)| matches(key, pattern))
+    }
+}
+
+/// Prefix of the annotations and labels with which workloads override the policy
+const OVERRIDE_PREFIX: &str = "sprinkler.k8s/";
+
+impl Rule {
+    /// Overrides a container carries, as pod annotations (annotation.sprinkler.k8s/*) or its own
+    /// labels (sprinkler.k8s/*)
+    ///
+    /// * `sprinkler.k8s/ignore=true` leaves the container alone
+    /// * `sprinkler.k8s/max-oom-rate=<Hz>` replaces oom_rate
+    /// * `sprinkler.k8s/max-retry=<n>` replaces max_retry
+    pub fn from_overrides(attributes: &HashMap<String, String>) -> Rule {
+        let get = |key: &str| attributes.get(&format!("annotation.{}{}", OVERRIDE_PREFIX, key))
+            .or_else(|| attributes.get(&format!("{}{}", OVERRIDE_PREFIX, key)));
+        fn parse<T: std::str::FromStr>(key: &str, value: Option<&String>) -> Option<T> {
+            let value = value?;
+            match value.trim().parse() {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    debug!("Ignoring {}{}={:?}", OVERRIDE_PREFIX, key, value);
+                    None
+                }
+            }
+        }
+        Rule {
+            action: match parse::<bool>("ignore", get("ignore")) {
+                Some(true) => Some(RuleAction::Ignore),
+                _ => None
+            },
+            oom_rate: parse::<f32>("max-oom-rate", get("max-oom-rate")).filter(|rate| *rate > 0.0),
+            max_retry: parse("max-retry", get("max-retry")),
+            ..Default::default()
+        }
+    }
+
+    /// Settings of this rule, or else those of another
+    pub fn or(self, other: &Rule) -> Rule {
+        Rule {
+            action: self.action.or(other.action),
+            oom_rate: self.oom_rate.or(other.oom_rate),
+            max_retry: self.max_retry.or(other.max_retry),
+            divider: self.divider.or(other.divider),
+            ..self
+        }
+    }
+}
+
+#[test]
+fn test_rule_overrides() {
+    let mut attributes = HashMap::new();
+    attributes.insert(String::from("io.kubernetes.pod.namespace"), String::from("batch"));
+    attributes.insert(String::from("annotation.sprinkler.k8s/max-oom-rate"), String::from("50"));
+    attributes.insert(String::from("annotation.sprinkler.k8s/max-retry"), String::from("many"));
+    attributes.insert(String::from("sprinkler.k8s/ignore"), String::from("false"));
+    let rule = Rule { action: Some(RuleAction::Evict), max_retry: Some(3), divider: Some(2), ..Default::default() };
+    assert_eq!(
+        Rule { action: Some(RuleAction::Evict), oom_rate: Some(50.0), max_retry: Some(3), divider: Some(2), ..Default::default() },
+        Rule::from_overrides(&attributes).or(&rule));
+
+    attributes.insert(String::from("annotation.sprinkler.k8s/ignore"), String::from("true"));
+    assert_eq!(Some(RuleAction::Ignore), Rule::from_overrides(&attributes).or(&rule).action);
+}
+
+/// Match text against a pattern where `*` stands for any string and `?` for any character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None; // Where to resume if what follows the last `*` fails to match
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        }
+        else if let Some((star, matched)) = backtrack {
+            // Let the `*` take one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        }
+        else { return false; }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("jupyter-*", "jupyter-alex"));
+    assert!(glob_match("jupyter-*", "jupyter-"));
+    assert!(!glob_match("jupyter-*", "jhub-alex"));
+    assert!(glob_match("*-prod", "jhub-prod"));
+    assert!(glob_match("k*-prod-cpu-?", "k8s-prod-cpu-1"));
+    assert!(!glob_match("k*-prod-cpu-?", "k8s-prod-cpu-12"));
+    assert!(glob_match("*a*b*", "xxaxxbxxb"));
+    assert!(glob_match("kube-system", "kube-system"));
+    assert!(!glob_match("kube-system", "kube-system2"));
+    assert!(glob_match("*", ""));
+}
+
+#[test]
+fn test_rule_matches() {
+    let mut attributes = HashMap::new();
+    attributes.insert(String::from("io.kubernetes.pod.namespace"), String::from("jhub-prod"));
+    attributes.insert(String::from("io.kubernetes.pod.name"), String::from("jupyter-alex"));
+    attributes.insert(String::from("component"), String::from("singleuser-server"));
+
+    assert!(Rule::default().matches(&attributes));
+    assert!(Rule { namespace: Some(String::from("jhub-*")), pod: Some(String::from("jupyter-*")), ..Default::default() }.matches(&attributes));
+    assert!(!Rule { namespace: Some(String::from("kube-system")), ..Default::default() }.matches(&attributes));
+
+    let mut labels = HashMap::new();
+    labels.insert(String::from("component"), String::from("singleuser-*"));
+    assert!(Rule { labels: labels.clone(), ..Default::default() }.matches(&attributes));
+    labels.insert(String::from("tier"), String::from("*"));
+    assert!(!Rule { labels, ..Default::default() }.matches(&attributes)); // Missing labels do not match
+
+    attributes.remove("io.kubernetes.pod.namespace");
+    assert!(!Rule { namespace: Some(String::from("*")), ..Default::default() }.matches(&attributes));
+}
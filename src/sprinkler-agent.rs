@@ -2,9 +2,38 @@
 extern crate clap;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 
 mod docker_oom;
+mod meter;
+mod http;
+mod kube;
+mod cgroup;
+mod rules;
+mod limiter;
+mod offenders;
+#[allow(dead_code)] // Only the master correlates reports
+mod incidents;
+#[allow(dead_code)] // Only the master sends alerts
+mod alerts;
 mod config;
+mod fleet;
+mod notification;
+mod report;
+mod outbox;
+mod metrics;
+#[allow(dead_code)] // Only the master serves the status
+mod status;
+#[allow(dead_code)] // Only the master stores reports
+mod store;
+
+/// Trust the certificate of the master when sending notifications
+fn load_master_cert(path: &std::path::Path) -> Result<(), config::ConfigError> {
+    let cert = config::load_cert(path)?;
+    *notification::MASTER_CERT.write().unwrap() = Some(cert);
+    Ok(())
+}
 
 fn main() {
     let args = clap_app!(sprinkler =>
@@ -12,12 +41,49 @@ fn main() {
             (author: crate_authors!())
             (about: crate_description!())
             (@arg VERBOSE: --verbose -v ... "Logging verbosity")
+            (@arg CONFIG: --config -c +takes_value "Configuration file")
         ).get_matches();
     config::setup_logger(args.occurrences_of("VERBOSE")).expect("Logger Error.");
+    let fname_config = args.value_of("CONFIG").unwrap_or(config::FNAME_CONFIG);
+    let config = match config::Config::load(fname_config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}: {}", fname_config, e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = load_master_cert(&config.master_cert) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
-    tokio::run(futures::future::lazy(|| {
-        let sprinklers = config::get_sprinklers();
-        sprinkler_api::agent(&sprinklers);
+    let fname_config = String::from(fname_config);
+    tokio::run(futures::future::lazy(move || {
+        outbox::retarget(&config.master_addr);
+        if let Err(e) = outbox::Outbox::install(&config.outbox) {
+            error!("{}: {}, notifications will not survive restarts", config.outbox.display(), e);
+        }
+        match http::serve(&config.agent_http_addr, |path| match path {
+            "/metrics" => http::Response::ok(metrics::CONTENT_TYPE, metrics::render()),
+            _ => http::Response::not_found()
+        }) {
+            Ok(server) => { tokio::spawn(server); }
+            Err(e) => error!("{}: {}, metrics will not be served", config.agent_http_addr, e)
+        }
+        let mut agent_http_addr = config.agent_http_addr; // As of the last reload
+        let mut fleet = fleet::Fleet::default();
+        fleet.reload(&config, |sprinklers| sprinkler_api::agent(sprinklers));
+        tokio::spawn(fleet::on_sighup(fname_config, move |config| {
+            if config.agent_http_addr != agent_http_addr {
+                warn!("agent_http_addr has changed, which requires restarting the agent");
+                agent_http_addr = config.agent_http_addr;
+            }
+            if let Err(e) = load_master_cert(&config.master_cert) {
+                error!("{}, keeping the current certificate", e);
+            }
+            outbox::retarget(&config.master_addr);
+            fleet.reload(&config, |sprinklers| sprinkler_api::agent(sprinklers));
+        }));
         Ok(())
     }));
 
@@ -2,10 +2,27 @@
 extern crate clap;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 
 use sprinkler_api::{Switch};
 mod docker_oom;
+mod meter;
+mod http;
+mod kube;
+mod cgroup;
+mod rules;
+mod limiter;
+mod offenders;
+mod incidents;
+mod alerts;
 mod config;
+mod fleet;
+mod notification;
+mod report;
+mod store;
+mod metrics;
+mod status;
 
 fn main() {
     let args = clap_app!(sprinkler =>
@@ -13,15 +30,101 @@ fn main() {
             (author: crate_authors!())
             (about: crate_description!())
             (@arg VERBOSE: --verbose -v ... "Logging verbosity")
+            (@arg CONFIG: --config -c +takes_value "Configuration file")
+            (@subcommand query =>
+                (about: "Filter and summarise the events the master has stored")
+                (@arg HOST: --host +takes_value "Only events of this host")
+                (@arg NAMESPACE: --namespace -n +takes_value "Only events of pods in this namespace")
+                (@arg POD: --pod +takes_value "Only events of pods whose names match this glob")
+                (@arg KIND: --kind +takes_value "Only anomaly, remediation, monitor or offender events")
+                (@arg ACTION: --action +takes_value "Only remediations taking this action, e.g. kill")
+                (@arg SINCE: --since +takes_value "Only events since then, e.g. 7d, 12h or 2026-10-12")
+                (@arg UNTIL: --until +takes_value "Only events until then")
+                (@arg SUMMARY: --summary -s "Count events by subject instead of listing them"))
         ).get_matches();
     config::setup_logger(args.occurrences_of("VERBOSE")).expect("Logger Error.");
+    let fname_config = args.value_of("CONFIG").unwrap_or(config::FNAME_CONFIG);
+    let config = match config::Config::load(fname_config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}: {}", fname_config, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(args) = args.subcommand_matches("query") {
+        std::process::exit(match query(&config.events, args) {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("{}", e);
+                1
+            }
+        });
+    }
+    if let Err(e) = alerts::install(&config.alerts) {
+        error!("{}: {}", fname_config, e);
+        std::process::exit(1);
+    }
+    if let Err(e) = store::install(&config.events) {
+        error!("{}: {}, reports will not be stored", config.events.display(), e);
+    }
+    incidents::install(&config.correlation);
 
-    tokio::run(futures::future::lazy(|| {
-        let sprinklers = config::get_sprinklers();
+    let fname_config = String::from(fname_config);
+    tokio::run(futures::future::lazy(move || {
+        let mut fleet = fleet::Fleet::default();
         let switch = Switch::new();
-        switch.connect_all(&sprinklers);
-        let addr = "0.0.0.0:3777".parse().unwrap();
-        sprinkler_api::server(&addr, &switch);
+        fleet.reload(&config, |sprinklers| switch.connect_all(sprinklers));
+        status::configure(fleet.specs(), config.offline_after);
+        sprinkler_api::server(&config.listen_addr, &switch);
+        match http::serve(&config.master_http_addr, |path| match path {
+            "/" => http::Response::ok("text/html; charset=utf-8", status::to_html()),
+            "/status" => http::Response::ok("application/json", status::to_json()),
+            "/metrics" => http::Response::ok(metrics::CONTENT_TYPE, metrics::render()),
+            _ => http::Response::not_found()
+        }) {
+            Ok(server) => { tokio::spawn(server); }
+            Err(e) => error!("{}: {}, metrics and status will not be served", config.master_http_addr, e)
+        }
+        let mut current = config; // Compared with on every reload
+        tokio::spawn(fleet::on_sighup(fname_config, move |mut new_config| {
+            if new_config.listen_addr != current.listen_addr {
+                warn!("listen_addr has changed, which requires restarting the master");
+            }
+            if new_config.master_http_addr != current.master_http_addr {
+                warn!("master_http_addr has changed, which requires restarting the master");
+            }
+            if let Err(e) = alerts::install(&new_config.alerts) {
+                error!("{}, keeping the current alert sinks", e);
+            }
+            if new_config.events != current.events {
+                if let Err(e) = store::install(&new_config.events) {
+                    error!("{}: {}, storing reports where they were", new_config.events.display(), e);
+                    new_config.events = current.events.clone(); // Try again upon the next reload
+                }
+            }
+            incidents::install(&new_config.correlation);
+            fleet.reload(&new_config, |sprinklers| switch.connect_all(sprinklers));
+            status::configure(fleet.specs(), new_config.offline_after);
+            current = new_config;
+        }));
         Ok(())
     }));
 }
+
+/// Print the stored events that match the arguments of the query subcommand
+fn query(path: &std::path::Path, args: &clap::ArgMatches) -> Result<(), String> {
+    let query = store::Query::from_args(args, chrono::Utc::now())?;
+    let events = store::Events::load(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let reports = events.select(&query);
+    if args.is_present("SUMMARY") {
+        for line in store::summarize(&reports) {
+            println!("{}", line);
+        }
+    }
+    else {
+        for report in reports {
+            println!("{} {} ({:?}) {}", report.time.format("%Y-%m-%d %H:%M:%S"), report.host, report.sprinkler, report.event);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use std::sync::Mutex;
+use std::collections::BTreeMap;
+use serde::Serialize;
+use tokio::prelude::*;
+use sprinkler_api::{Sprinkler, SprinklerOptions, ActivationResult, Message};
+use crate::config::{SprinklerSpec, SprinklerType};
+#[cfg(test)]
+use crate::config::Config;
+use crate::report::{Report, Event, Transition, MonitorStatus};
+#[cfg(test)]
+use crate::report::{Subject, PodIdentity, REPORT_VERSION};
+
+/// What the master knows of a sprinkler
+#[derive(Clone, Debug)]
+struct Record {
+    id: usize,
+    typ: SprinklerType,
+    hostname: String,
+    last_contact: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last reported health, if it is not monitoring as usual
+    monitor: Option<MonitorStatus>,
+    /// Last transition of subjects that have an anomaly, by subject
+    anomalies: BTreeMap<String, Transition>
+}
+
+/// Last known state of every configured sprinkler, in the order of the configuration
+#[derive(Default)]
+pub struct Status {
+    records: Vec<Record>,
+    /// Seconds without hearing from a CommCheck agent after which its host is considered offline
+    offline_after: i64
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HostStatus {
+    pub hostname: String,
+    pub sprinklers: Vec<SprinklerStatus>
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SprinklerStatus {
+    pub id: usize,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// unknown until the agent is heard from, then online or offline for CommCheck,
+    /// and monitoring, degraded, breaker_tripped or deactivated for DockerOOM
+    pub state: String,
+    pub last_contact: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last transition of subjects that have an anomaly, by subject
+    pub anomalies: BTreeMap<String, Transition>
+}
+
+impl Status {
+    /// Follow the sprinklers of the fleet, keeping what is known of unchanged ones
+    pub fn configure(&mut self, specs: &[SprinklerSpec], offline_after: u64) {
+        self.offline_after = offline_after as i64;
+        let records = specs.iter().map(|spec| match self.records.iter().find(|record| record.id == spec.id) {
+            Some(record) if record.typ == spec.typ && record.hostname == spec.hostname => record.clone(),
+            _ => Record {
+                id: spec.id, typ: spec.typ, hostname: spec.hostname.clone(), last_contact: None, monitor: None, anomalies: BTreeMap::new()
+            }
+        }).collect();
+        self.records = records;
+    }
+
+    pub fn contact(&mut self, id: usize, now: chrono::DateTime<chrono::Utc>) {
+        if let Some(record) = self.records.iter_mut().find(|record| record.id == id) {
+            record.last_contact = Some(now);
+        }
+    }
+
+    pub fn record(&mut self, id: usize, report: &Report) {
+        let record = match self.records.iter_mut().find(|record| record.id == id) {
+            Some(record) => record,
+            None => return
+        };
+        match &report.event {
+            Event::Anomaly { subject, transition } if transition.is_anomalous() => {
+                record.anomalies.insert(subject.to_string(), *transition);
+            }
+            Event::Anomaly { subject, .. } => { record.anomalies.remove(&subject.to_string()); }
+            Event::Monitor { status, .. } => record.monitor = match status {
+                MonitorStatus::Recovered | MonitorStatus::BreakerReset => None,
+                status => Some(*status)
+            },
+            _ => ()
+        }
+    }
+
+    /// Configured hosts in order, along with their sprinklers
+    pub fn hosts(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<HostStatus> {
+        let mut hosts: Vec<HostStatus> = Vec::new();
+        for record in self.records.iter() {
+            let state = match (record.last_contact, record.typ, &record.monitor) {
+                (None, _, _) => "unknown",
+                (Some(t), SprinklerType::CommCheck, _) =>
+                    if (now - t).num_seconds() > self.offline_after { "offline" } else { "online" },
+                (Some(_), _, None) => "monitoring",
+                (Some(_), _, Some(MonitorStatus::Degraded)) => "degraded",
+                (Some(_), _, Some(MonitorStatus::BreakerTripped)) => "breaker_tripped",
+                (Some(_), _, Some(_)) => "deactivated"
+            };
+            let sprinkler = SprinklerStatus {
+                id: record.id,
+                typ: format!("{:?}", record.typ),
+                state: String::from(state),
+                last_contact: record.last_contact,
+                anomalies: record.anomalies.clone()
+            };
+            match hosts.iter_mut().find(|host| host.hostname == record.hostname) {
+                Some(host) => host.sprinklers.push(sprinkler),
+                None => hosts.push(HostStatus { hostname: record.hostname.clone(), sprinklers: vec![sprinkler] })
+            }
+        }
+        hosts
+    }
+}
+
+#[test]
+fn test_status() {
+    let config = Config::parse(r#"
+        master_addr = "localhost:3777"
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1"
+        sprinklers = ["CommCheck", "DockerOOM"]
+
+        [[hosts]]
+        hostname = "k-prod-cpu-2"
+        sprinklers = ["CommCheck"]
+    "#).unwrap();
+    let now = "2026-10-12T10:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    let report = |event: Event| Report {
+        version: REPORT_VERSION, sprinkler: SprinklerType::DockerOOM, host: String::from("k-prod-cpu-1"), time: now, event
+    };
+    let anomaly = |name: &str, transition| report(Event::Anomaly {
+        subject: Subject::Pod(PodIdentity { namespace: String::from("jhub-prod"), name: String::from(name), ..Default::default() }),
+        transition
+    });
+
+    let mut status = Status::default();
+    status.configure(&config.specs(), config.offline_after);
+    status.contact(0, now - chrono::Duration::seconds(60));
+    status.contact(1, now);
+    status.record(1, &anomaly("jupyter-alex", Transition::Occurred));
+    status.record(1, &anomaly("jupyter-bob", Transition::Occurred));
+    status.record(1, &anomaly("jupyter-alex", Transition::Fixing));
+    status.record(1, &anomaly("jupyter-bob", Transition::Fixed));
+    status.record(1, &report(Event::Monitor { status: MonitorStatus::Degraded, reason: None }));
+    let hosts = status.hosts(now);
+    assert_eq!(vec!["k-prod-cpu-1", "k-prod-cpu-2"], hosts.iter().map(|host| host.hostname.as_str()).collect::<Vec<_>>());
+    assert_eq!("offline", hosts[0].sprinklers[0].state);
+    assert_eq!("degraded", hosts[0].sprinklers[1].state);
+    assert_eq!(Some(now), hosts[0].sprinklers[1].last_contact);
+    assert_eq!(vec![(&String::from("pod jhub-prod/jupyter-alex"), &Transition::Fixing)],
+               hosts[0].sprinklers[1].anomalies.iter().collect::<Vec<_>>());
+    assert_eq!("unknown", hosts[1].sprinklers[0].state);
+
+    // Sprinklers that keep their id, type and host keep their state across reloads,
+    // and CommCheck agents are given as long as the configuration says
+    let config = Config::parse(r#"
+        master_addr = "localhost:3777"
+        offline_after = 90
+
+        [[hosts]]
+        hostname = "k-prod-cpu-1"
+        sprinklers = ["CommCheck", "DockerOOM"]
+    "#).unwrap();
+    status.configure(&config.specs(), config.offline_after);
+    status.record(1, &report(Event::Monitor { status: MonitorStatus::Recovered, reason: None }));
+    let hosts = status.hosts(now);
+    assert_eq!(1, hosts.len());
+    assert_eq!("online", hosts[0].sprinklers[0].state);
+    assert_eq!("monitoring", hosts[0].sprinklers[1].state);
+    assert_eq!(1, hosts[0].sprinklers[1].anomalies.len());
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<Status> = Mutex::new(Status::default());
+}
+
+/// Follow the sprinklers of the fleet from now on
+pub fn configure(specs: &[SprinklerSpec], offline_after: u64) {
+    STATUS.lock().unwrap().configure(specs, offline_after);
+}
+
+/// Note that the agent of a sprinkler has been heard from
+pub fn contact(id: usize) {
+    STATUS.lock().unwrap().contact(id, chrono::Utc::now());
+}
+
+/// Note what the agent of a sprinkler has reported
+pub fn record(id: usize, report: &Report) {
+    STATUS.lock().unwrap().record(id, report);
+}
+
+pub fn to_json() -> String {
+    serde_json::to_string_pretty(&STATUS.lock().unwrap().hosts(chrono::Utc::now())).unwrap_or_default()
+}
+
+/// Page with a table of the sprinklers of every host
+pub fn to_html() -> String {
+    let now = chrono::Utc::now();
+    let mut rows = String::new();
+    for host in STATUS.lock().unwrap().hosts(now) {
+        for sprinkler in host.sprinklers {
+            let last_contact = sprinkler.last_contact
+                .map(|t| format!("{} ({}s ago)", t.format("%Y-%m-%d %H:%M:%S UTC"), (now - t).num_seconds()))
+                .unwrap_or_else(|| String::from("never"));
+            let anomalies: Vec<String> = sprinkler.anomalies.iter()
+                .map(|(subject, transition)| escape(&format!("{} ({:?})", subject, transition)))
+                .collect();
+            rows.push_str(&format!(
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                sprinkler.state, escape(&host.hostname), sprinkler.id, sprinkler.typ, sprinkler.state,
+                last_contact, anomalies.join("<br>")));
+        }
+    }
+    format!(concat!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Sprinklers</title>\n",
+        "<style>td, th {{ padding: 2px 8px; text-align: left; }} .offline, .degraded, .breaker_tripped {{ color: #c00; }}</style>\n",
+        "</head><body>\n<table>\n<tr><th>Host</th><th>Id</th><th>Sprinkler</th><th>State</th><th>Last contact</th><th>Anomalies</th></tr>\n",
+        "{}</table>\n</body></html>\n"), rows)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Sprinkler whose agent is noted as heard from whenever the master receives its messages
+#[derive(Clone)]
+pub struct Watched<S>(S);
+
+impl<S: Sprinkler> Sprinkler for Watched<S> {
+    fn build(options: SprinklerOptions) -> Self {
+        Watched(S::build(options))
+    }
+
+    fn id(&self) -> usize {
+        self.0.id()
+    }
+
+    fn hostname(&self) -> &str {
+        self.0.hostname()
+    }
+
+    fn activate_master(&self) -> ActivationResult {
+        let id = self.id();
+        let (tx, rx) = futures::sync::mpsc::channel::<Message>(512);
+        let watch = |inner: futures::sync::mpsc::Sender<Message>| rx
+            .map(move |message| {
+                contact(id);
+                message
+            })
+            .forward(inner.sink_map_err(|_| ()))
+            .map(|_| ());
+        match self.0.activate_master() {
+            ActivationResult::RealtimeMonitor(inner) => {
+                tokio::spawn(watch(inner));
+                ActivationResult::RealtimeMonitor(tx)
+            }
+            ActivationResult::AsyncMonitor(inner) => {
+                tokio::spawn(watch(inner));
+                ActivationResult::AsyncMonitor(tx)
+            }
+        }
+    }
+
+    fn activate_agent(&self) {
+        self.0.activate_agent();
+    }
+
+    fn deactivate(&self) {
+        self.0.deactivate();
+    }
+}
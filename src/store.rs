@@ -0,0 +1,380 @@
+use std::io::{self, BufRead, Write};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use crate::report::{Report, Event, Subject, Action, Outcome, DecodeError};
+use crate::rules::glob_match;
+
+/// Append-only log of the reports the master has received, one JSON report per line
+pub struct EventLog {
+    file: File
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(EventLog { file: OpenOptions::new().create(true).append(true).open(path)? })
+    }
+
+    pub fn append(&mut self, report: &Report) -> io::Result<()> {
+        let mut line = report.encode().into_bytes();
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+lazy_static! {
+    /// Event log of this process, without which reports are only logged
+    static ref EVENT_LOG: Mutex<Option<EventLog>> = Mutex::new(None);
+}
+
+/// Store reports at `path` from now on
+pub fn install(path: &Path) -> io::Result<()> {
+    *EVENT_LOG.lock().unwrap() = Some(EventLog::open(path)?);
+    Ok(())
+}
+
+/// Store a report if there is an event log
+pub fn record(report: &Report) {
+    if let Some(log) = &mut *EVENT_LOG.lock().unwrap() {
+        if let Err(e) = log.append(report) {
+            error!("Unable to store a report: {}", e);
+        }
+    }
+}
+
+/// Reports read back from an event log, indexed by host, namespace, pod and time
+pub struct Events {
+    reports: Vec<Report>,
+    by_host: HashMap<String, Vec<usize>>,
+    by_namespace: HashMap<String, Vec<usize>>,
+    /// By <namespace>/<name>
+    by_pod: HashMap<String, Vec<usize>>,
+    /// Positions of the reports in the order of their time
+    by_time: Vec<usize>,
+    /// Records that could not be read, by their version, or None for malformed ones
+    skipped: BTreeMap<Option<u32>, usize>
+}
+
+impl Events {
+    /// Read back an event log, bringing reports of older versions up to date
+    pub fn load(path: &Path) -> io::Result<Events> {
+        let mut reports = Vec::new();
+        let mut skipped = BTreeMap::new();
+        for (i, line) in io::BufReader::new(File::open(path)?).lines().enumerate() {
+            match Report::decode(&line?) {
+                Ok(report) => reports.push(report),
+                Err(e) => {
+                    debug!("{}:{}: skipping a record: {}", path.display(), i + 1, e);
+                    let version = match e {
+                        DecodeError::Version(version) => Some(version),
+                        DecodeError::Json(_) => None
+                    };
+                    *skipped.entry(version).or_insert(0) += 1;
+                }
+            }
+        }
+        for (version, n) in skipped.iter() {
+            match version {
+                Some(version) => warn!("{}: skipped {} records of unsupported version {}", path.display(), n, version),
+                None => warn!("{}: skipped {} malformed records", path.display(), n)
+            }
+        }
+        let mut events = Events::index(reports);
+        events.skipped = skipped;
+        Ok(events)
+    }
+
+    fn index(reports: Vec<Report>) -> Events {
+        let mut events = Events {
+            by_host: HashMap::new(),
+            by_namespace: HashMap::new(),
+            by_pod: HashMap::new(),
+            by_time: (0..reports.len()).collect(),
+            skipped: BTreeMap::new(),
+            reports
+        };
+        for (i, report) in events.reports.iter().enumerate() {
+            events.by_host.entry(report.host.clone()).or_default().push(i);
+            if let Some(Subject::Pod(pod)) = subject_of(&report.event) {
+                events.by_namespace.entry(pod.namespace.clone()).or_default().push(i);
+                events.by_pod.entry(format!("{}/{}", pod.namespace, pod.name)).or_default().push(i);
+            }
+        }
+        let reports = &events.reports;
+        events.by_time.sort_by_key(|i| reports[*i].time); // Agents report in their own order
+        events
+    }
+
+    /// Reports matching a query in the order of their time
+    pub fn select(&self, query: &Query) -> Vec<&Report> {
+        let literal = |pattern: &str| !pattern.contains(&['*', '?'][..]);
+        // Narrow the search down by the most selective index at hand
+        let mut candidates: Vec<&Vec<usize>> = Vec::new();
+        let empty = Vec::new();
+        if let Some(host) = &query.host {
+            candidates.push(self.by_host.get(host).unwrap_or(&empty));
+        }
+        if let Some(namespace) = &query.namespace {
+            candidates.push(self.by_namespace.get(namespace).unwrap_or(&empty));
+            if let Some(pod) = query.pod.as_ref().filter(|pod| literal(pod)) {
+                candidates.push(self.by_pod.get(&format!("{}/{}", namespace, pod)).unwrap_or(&empty));
+            }
+        }
+        let start = self.by_time.partition_point(|i| query.since.map_or(false, |since| self.reports[*i].time < since));
+        let end = self.by_time.partition_point(|i| query.until.map_or(true, |until| self.reports[*i].time <= until));
+        let in_time: Vec<usize> = match candidates.into_iter().min_by_key(|positions| positions.len()) {
+            Some(positions) if positions.len() < end.saturating_sub(start) => {
+                let mut positions = positions.clone();
+                positions.sort_by_key(|i| self.reports[*i].time);
+                positions
+            }
+            _ => self.by_time[start..end.max(start)].to_vec()
+        };
+        in_time.into_iter().map(|i| &self.reports[i]).filter(|report| query.matches(report)).collect()
+    }
+}
+
+fn subject_of(event: &Event) -> Option<&Subject> {
+    match event {
+        Event::Anomaly { subject, .. } | Event::Remediation { subject, .. } | Event::Offender { subject, .. } => Some(subject),
+        Event::Monitor { .. } => None
+    }
+}
+
+/// Kinds of events, as in the JSON of reports
+const KINDS: [&str; 4] = ["anomaly", "remediation", "monitor", "offender"];
+
+fn kind_of(event: &Event) -> &'static str {
+    match event {
+        Event::Anomaly { .. } => KINDS[0],
+        Event::Remediation { .. } => KINDS[1],
+        Event::Monitor { .. } => KINDS[2],
+        Event::Offender { .. } => KINDS[3]
+    }
+}
+
+/// Conditions on the reports to select, all of which have to be met
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub host: Option<String>,
+    pub namespace: Option<String>,
+    /// Glob of the name of the pod
+    pub pod: Option<String>,
+    pub kind: Option<String>,
+    /// Only remediations taking this action
+    pub action: Option<Action>,
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub until: Option<chrono::DateTime<chrono::Utc>>
+}
+
+impl Query {
+    pub fn from_args(args: &clap::ArgMatches, now: chrono::DateTime<chrono::Utc>) -> Result<Query, String> {
+        let time = |name: &str| args.value_of(name).map(|value| parse_time(value, now)).transpose();
+        let kind = args.value_of("KIND").map(String::from);
+        if let Some(kind) = &kind {
+            if !KINDS.contains(&kind.as_str()) {
+                return Err(format!("kind must be one of {}, got {:?}", KINDS.join(", "), kind));
+            }
+        }
+        Ok(Query {
+            host: args.value_of("HOST").map(String::from),
+            namespace: args.value_of("NAMESPACE").map(String::from),
+            pod: args.value_of("POD").map(String::from),
+            kind,
+            action: args.value_of("ACTION")
+                .map(|action| serde_json::from_value(serde_json::json!(action.to_lowercase()))
+                    .map_err(|_| format!("unknown action {:?}", action)))
+                .transpose()?,
+            since: time("SINCE")?,
+            until: time("UNTIL")?
+        })
+    }
+
+    fn matches(&self, report: &Report) -> bool {
+        let pod = match subject_of(&report.event) {
+            Some(Subject::Pod(pod)) => Some(pod),
+            _ => None
+        };
+        self.host.as_ref().map_or(true, |host| *host == report.host)
+            && self.namespace.as_ref().map_or(true, |namespace| pod.map_or(false, |pod| pod.namespace == *namespace))
+            && self.pod.as_ref().map_or(true, |pattern| pod.map_or(false, |pod| glob_match(pattern, &pod.name)))
+            && self.kind.as_ref().map_or(true, |kind| kind == kind_of(&report.event))
+            && self.action.map_or(true, |action| match &report.event {
+                Event::Remediation { action: a, .. } => *a == action,
+                _ => false
+            })
+            && self.since.map_or(true, |since| report.time >= since)
+            && self.until.map_or(true, |until| report.time <= until)
+    }
+}
+
+/// Parse a point in time, either relative to now like 7d, 12h, 30m and 45s, or as in 2026-10-12
+/// and 2026-10-12T08:00:00Z
+fn parse_time(text: &str, now: chrono::DateTime<chrono::Utc>) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    let invalid = || format!("invalid time {:?}, expecting e.g. 7d, 12h, 2026-10-12 or 2026-10-12T08:00:00Z", text);
+    if let Some(unit) = text.chars().last().filter(|c| "dhms".contains(*c)) {
+        if let Ok(n) = text[..text.len() - 1].parse::<i64>() {
+            let ago = match unit {
+                'd' => chrono::Duration::days(n),
+                'h' => chrono::Duration::hours(n),
+                'm' => chrono::Duration::minutes(n),
+                _ => chrono::Duration::seconds(n)
+            };
+            return Ok(now - ago);
+        }
+    }
+    chrono::DateTime::parse_from_rfc3339(text)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(&format!("{}T00:00:00Z", text)))
+        .map(|time| time.with_timezone(&chrono::Utc))
+        .map_err(|_| invalid())
+}
+
+/// Short name of what an event says, under which it is counted
+fn label_of(event: &Event) -> String {
+    match event {
+        Event::Anomaly { transition, .. } => format!("{:?}", transition),
+        Event::Remediation { action, outcome, .. } => match outcome {
+            Outcome::Succeeded => format!("{:?}", action),
+            Outcome::Failed(_) => format!("{:?} failed", action),
+            Outcome::DryRun => format!("{:?} (dry run)", action),
+            Outcome::Suppressed(_) => format!("{:?} (suppressed)", action)
+        },
+        Event::Monitor { status, .. } => format!("{:?}", status),
+        Event::Offender { block: true, .. } => String::from("Blocked"),
+        Event::Offender { .. } => String::from("Offender")
+    }
+}
+
+/// Count reports by subject and what they say, e.g.
+///
+/// ```text
+/// pod jhub-prod/jupyter-alex: Kill 3, Occurred 4, Remove 3 (last 2026-10-17 21:04:11 UTC)
+/// ```
+pub fn summarize(reports: &[&Report]) -> Vec<String> {
+    let mut subjects: BTreeMap<String, (BTreeMap<String, usize>, chrono::DateTime<chrono::Utc>)> = BTreeMap::new();
+    for report in reports {
+        let subject = match subject_of(&report.event) {
+            Some(subject) => format!("{}", subject),
+            None => format!("host {}", report.host)
+        };
+        let (counts, last) = subjects.entry(subject).or_insert_with(|| (BTreeMap::new(), report.time));
+        *counts.entry(label_of(&report.event)).or_insert(0) += 1;
+        *last = std::cmp::max(*last, report.time);
+    }
+    subjects.into_iter().map(|(subject, (counts, last))| {
+        let counts: Vec<String> = counts.into_iter().map(|(label, n)| format!("{} {}", label, n)).collect();
+        format!("{}: {} (last {})", subject, counts.join(", "), last.format("%Y-%m-%d %H:%M:%S UTC"))
+    }).collect()
+}
+
+#[cfg(test)]
+fn mock_reports() -> Vec<Report> {
+    use crate::report::PodIdentity;
+    let at = |day: u32, hour: u32| format!("2026-10-{:02}T{:02}:00:00Z", day, hour).parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    let pod = |namespace: &str, name: &str| Subject::Pod(PodIdentity {
+        namespace: String::from(namespace), name: String::from(name), uid: String::from("5f3c"), ..Default::default()
+    });
+    let kill = |subject: Subject| Event::Remediation {
+        subject, container: String::from("4c01db0b339c"), action: Action::Kill, outcome: Outcome::Succeeded
+    };
+    let report = |host: &str, time, event| Report { time, ..Report::new(crate::config::SprinklerType::DockerOOM, host, event) };
+    vec![
+        report("k-prod-cpu-2.dsa.lan", at(17, 9), kill(pod("jhub-prod", "jupyter-alex"))),
+        report("k-prod-cpu-1.dsa.lan", at(3, 12), kill(pod("jhub-prod", "jupyter-bob"))),
+        report("k-prod-cpu-1.dsa.lan", at(16, 8), Event::Anomaly { subject: pod("jhub-prod", "jupyter-alex"), transition: crate::report::Transition::Occurred }),
+        report("k-prod-cpu-1.dsa.lan", at(16, 8), kill(pod("jhub-prod", "jupyter-alex"))),
+        report("k-prod-cpu-1.dsa.lan", at(16, 10), kill(pod("batch", "etl-7d9f8b6c5-x2x4z"))),
+        report("k-prod-cpu-1.dsa.lan", at(16, 11), Event::Monitor { status: crate::report::MonitorStatus::Degraded, reason: None })
+    ]
+}
+
+#[test]
+fn test_event_log() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}-events.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let reports = mock_reports();
+    let mut log = EventLog::open(&path).unwrap();
+    for report in reports.iter() {
+        log.append(report).unwrap();
+    }
+    drop(log);
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"{\"version\":2,\"sprink").unwrap(); // Cut short by a crash
+
+    let events = Events::load(&path).unwrap();
+    assert_eq!(reports, events.reports);
+    assert_eq!(vec![1, 2, 3, 4, 5, 0], events.by_time);
+    assert_eq!(Some(&vec![0, 2, 3]), events.by_pod.get("jhub-prod/jupyter-alex"));
+    assert_eq!(vec![(&None, &1)], events.skipped.iter().collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_event_log_versions() {
+    let path = std::env::temp_dir().join(format!("sprinkler-test-{}-versions.log", std::process::id()));
+    let pod = r#"{"type":"pod","namespace":"jhub-prod","name":"jupyter-alex","uid":"5f3c"}"#;
+    let lines = vec![
+        format!(concat!(r#"{{"version":1,"sprinkler":"DockerOOM","host":"k-prod-cpu-1.dsa.lan","time":"2026-10-01T08:00:00Z","#,
+                        r#""event":{{"kind":"remediation","subject":{},"container":"4c01db0b339c","action":"kill_and_remove","outcome":"succeeded"}}}}"#), pod),
+        format!(concat!(r#"{{"version":2,"sprinkler":"DockerOOM","host":"k-prod-cpu-1.dsa.lan","time":"2026-10-08T08:00:00Z","#,
+                        r#""event":{{"kind":"anomaly","subject":{},"transition":"Occurred"}}}}"#), pod),
+        mock_reports()[0].encode(),
+        String::from(r#"{"version":99,"sprinkler":"DockerOOM","host":"k-prod-cpu-1.dsa.lan","time":"2026-10-18T08:00:00Z"}"#),
+        String::from(r#"{"version":99,"sprinkler":"DockerOOM","host":"k-prod-cpu-2.dsa.lan","time":"2026-10-18T08:00:00Z"}"#)
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let events = Events::load(&path).unwrap();
+    let query = Query { pod: Some(String::from("jupyter-alex")), ..Default::default() };
+    assert_eq!(
+        vec![String::from("pod jhub-prod/jupyter-alex: Kill 2, Occurred 1 (last 2026-10-17 09:00:00 UTC)")],
+        summarize(&events.select(&query)));
+    assert_eq!(vec![(&Some(99), &2)], events.skipped.iter().collect::<Vec<_>>());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_events_select() {
+    let events = Events::index(mock_reports());
+    let now = "2026-10-18T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    let positions = |query: &Query| events.select(query).into_iter()
+        .map(|report| events.reports.iter().position(|r| r == report).unwrap())
+        .collect::<Vec<usize>>();
+
+    // Which jhub-prod users were killed this week
+    let query = Query {
+        namespace: Some(String::from("jhub-prod")),
+        action: Some(Action::Kill),
+        since: Some(parse_time("7d", now).unwrap()),
+        ..Default::default()
+    };
+    assert_eq!(vec![3, 0], positions(&query));
+    assert_eq!(
+        vec![String::from("pod jhub-prod/jupyter-alex: Kill 2 (last 2026-10-17 09:00:00 UTC)")],
+        summarize(&events.select(&query)));
+
+    assert_eq!(vec![1, 2, 3, 0], positions(&Query { pod: Some(String::from("jupyter-*")), ..Default::default() }));
+    assert_eq!(vec![2, 3], positions(&Query {
+        host: Some(String::from("k-prod-cpu-1.dsa.lan")),
+        namespace: Some(String::from("jhub-prod")),
+        pod: Some(String::from("jupyter-alex")),
+        ..Default::default()
+    }));
+    assert_eq!(vec![5], positions(&Query { kind: Some(String::from("monitor")), ..Default::default() }));
+    assert_eq!(vec![4, 5], positions(&Query { since: Some(parse_time("2026-10-16T09:00:00Z", now).unwrap()), until: Some(parse_time("1d", now).unwrap()), ..Default::default() }));
+    assert_eq!(Vec::<usize>::new(), positions(&Query { namespace: Some(String::from("kube-system")), ..Default::default() }));
+}
+
+#[test]
+fn test_parse_time() {
+    let now = "2026-10-18T12:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    assert_eq!(now - chrono::Duration::days(7), parse_time("7d", now).unwrap());
+    assert_eq!(now - chrono::Duration::minutes(30), parse_time("30m", now).unwrap());
+    assert_eq!("2026-10-12T00:00:00+00:00", parse_time("2026-10-12", now).unwrap().to_rfc3339());
+    assert_eq!("2026-10-12T06:00:00+00:00", parse_time("2026-10-12T08:00:00+02:00", now).unwrap().to_rfc3339());
+    assert!(parse_time("last week", now).is_err());
+}